@@ -37,8 +37,30 @@ pub trait BasePacket: BufMut + Clone + Send {
     /// Returns a mutable byte slice of the payload.
     fn payload_mut(&mut self) -> &mut [u8];
 
+    /// Shortens the payload to `payload_len` bytes.
+    ///
+    /// The default implementation copies the payload into a new packet, so
+    /// packets with a header should shorten the underlying buffer instead.
+    fn truncate(&mut self, payload_len: usize) {
+        if payload_len < self.payload().len() {
+            let mut packet = Self::new(payload_len);
+            packet.put_slice(&self.payload()[..payload_len]);
+            *self = packet;
+        }
+    }
+
     /// Used for pretty printing the package.
     fn debug(&self, ds: &mut std::fmt::DebugStruct);
+
+    /// Returns `true` if the packet was received with a congestion
+    /// experienced mark. Packets of transports without explicit congestion
+    /// notification are never marked.
+    fn ecn(&self) -> bool {
+        false
+    }
+
+    /// Requests the packet to be sent with an ECN capable transport mark.
+    fn set_ecn(&mut self, _ecn: bool) {}
 }
 
 /// Packet trait is used to encapsulate packets into a lower layer packet.
@@ -67,6 +89,10 @@ impl BasePacket for BytesMut {
         &mut self[..]
     }
 
+    fn truncate(&mut self, payload_len: usize) {
+        BytesMut::truncate(self, payload_len)
+    }
+
     fn debug(&self, _: &mut std::fmt::DebugStruct) {}
 }
 
@@ -189,6 +215,10 @@ mod tests {
             &mut self.0.payload_mut()[1..]
         }
 
+        fn truncate(&mut self, payload_len: usize) {
+            self.0.truncate(payload_len + 1)
+        }
+
         fn debug(&self, ds: &mut std::fmt::DebugStruct) {
             self.0.debug(ds);
             ds.field("channel", &self.0.payload()[0]);
@@ -243,6 +273,10 @@ mod tests {
             &mut self.0.payload_mut()[8..]
         }
 
+        fn truncate(&mut self, payload_len: usize) {
+            self.0.truncate(payload_len + 8)
+        }
+
         fn debug(&self, ds: &mut std::fmt::DebugStruct) {
             self.0.debug(ds);
             ds.field("nonce", &self.nonce());
@@ -290,4 +324,39 @@ mod tests {
             assert_eq!(ch.channel(), 0);
         });
     }
+
+    /// Packet without a header that keeps the default `truncate`.
+    #[derive(Clone)]
+    struct RawPacket<P: BasePacket>(P);
+
+    impl<P: BasePacket> BasePacket for RawPacket<P> {
+        fn new(payload_len: usize) -> Self {
+            Self(P::new(payload_len))
+        }
+
+        fn check(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn payload(&self) -> &[u8] {
+            self.0.payload()
+        }
+
+        fn payload_mut(&mut self) -> &mut [u8] {
+            self.0.payload_mut()
+        }
+
+        fn debug(&self, _: &mut std::fmt::DebugStruct) {}
+    }
+
+    derive_packet!(RawPacket);
+
+    #[test]
+    fn test_truncate() {
+        let mut packet = RawPacket::<BytesMut>::from("ping");
+        packet.truncate(8);
+        assert_eq!(packet.payload(), b"ping");
+        packet.truncate(2);
+        assert_eq!(packet.payload(), b"pi");
+    }
 }
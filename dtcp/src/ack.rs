@@ -0,0 +1,114 @@
+//! Ack policies.
+use std::time::{Duration, Instant};
+
+/// Policy deciding when received transfer PDUs are acknowledged.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AckPolicy {
+    /// Every transfer PDU is acked immediately. Suited for interactive
+    /// traffic.
+    Immediate,
+    /// Acks are coalesced until the A-timer expires or `max_packets` packets
+    /// have been received. Suited for bulk transfers.
+    ///
+    /// Out of order and duplicate packets are acked immediately.
+    Delayed {
+        /// Maximum number of packets acknowledged by a single ack.
+        max_packets: u16,
+    },
+}
+
+impl Default for AckPolicy {
+    fn default() -> Self {
+        AckPolicy::Immediate
+    }
+}
+
+/// A-timer for incoming transfer PDUs.
+pub(crate) struct AckTimer {
+    policy: AckPolicy,
+    /// Number of received packets that have not been acked.
+    pending: u16,
+    /// When the pending packets must be acked.
+    deadline: Option<Instant>,
+}
+
+impl AckTimer {
+    pub fn new(policy: AckPolicy) -> Self {
+        Self {
+            policy,
+            pending: 0,
+            deadline: None,
+        }
+    }
+
+    /// Registers a received transfer PDU and returns `true` if an ack needs
+    /// to be sent immediately.
+    ///
+    /// `in_order` is `false` for duplicates and packets leaving a gap.
+    /// `interval` is the A-timer interval.
+    pub fn received(&mut self, in_order: bool, interval: Duration, now: Instant) -> bool {
+        let max_packets = match self.policy {
+            AckPolicy::Immediate => return true,
+            AckPolicy::Delayed { max_packets } => max_packets,
+        };
+        if !in_order {
+            return true;
+        }
+        self.pending = self.pending.saturating_add(1);
+        if self.pending >= max_packets {
+            return true;
+        }
+        if self.deadline.is_none() {
+            self.deadline = Some(now + interval);
+        }
+        false
+    }
+
+    /// Returns when the A-timer expires.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns `true` if the A-timer expired.
+    pub fn expired(&self, now: Instant) -> bool {
+        match self.deadline {
+            Some(deadline) => deadline <= now,
+            None => false,
+        }
+    }
+
+    /// Stops the A-timer when an ack was sent.
+    pub fn acked(&mut self) {
+        self.pending = 0;
+        self.deadline = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_immediate() {
+        let now = Instant::now();
+        let mut timer = AckTimer::new(AckPolicy::Immediate);
+        assert!(timer.received(true, Duration::from_millis(10), now));
+        assert!(timer.deadline().is_none());
+    }
+
+    #[test]
+    fn test_delayed() {
+        let now = Instant::now();
+        let interval = Duration::from_millis(10);
+        let mut timer = AckTimer::new(AckPolicy::Delayed { max_packets: 3 });
+        assert!(!timer.received(true, interval, now));
+        assert!(!timer.received(true, interval, now + interval / 2));
+        assert_eq!(timer.deadline(), Some(now + interval));
+        assert!(!timer.expired(now));
+        assert!(timer.expired(now + interval));
+        assert!(timer.received(true, interval, now));
+        timer.acked();
+        assert!(timer.deadline().is_none());
+        assert!(timer.received(false, interval, now));
+    }
+}
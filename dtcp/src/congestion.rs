@@ -0,0 +1,343 @@
+//! Congestion control.
+use std::time::{Duration, Instant};
+
+/// Initial congestion window in packets.
+const INITIAL_WINDOW: u32 = 10;
+/// Minimum congestion window in packets after a loss.
+const MIN_WINDOW: u32 = 2;
+
+/// Congestion control algorithm.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CongestionControl {
+    /// NewReno additive increase multiplicative decrease (RFC 6582).
+    NewReno,
+    /// CUBIC window growth function (RFC 8312).
+    Cubic,
+}
+
+impl CongestionControl {
+    pub(crate) fn build(self) -> Box<dyn CongestionController> {
+        match self {
+            CongestionControl::NewReno => Box::new(NewReno::new()),
+            CongestionControl::Cubic => Box::new(Cubic::new()),
+        }
+    }
+}
+
+impl Default for CongestionControl {
+    fn default() -> Self {
+        CongestionControl::NewReno
+    }
+}
+
+/// Congestion controller driven by the ack, loss and rtt events of a dtcp
+/// channel.
+///
+/// Losses are reported at most once per window of data. The recovery from a
+/// loss ends once all packets sent before the loss are acknowledged. Custom
+/// controllers are set with `DtcpBuilder::set_congestion_controller`.
+pub trait CongestionController: Send + CloneCongestionController {
+    /// Returns the congestion window in packets.
+    fn window(&self) -> u32;
+
+    /// Called when `acked` packets were acknowledged.
+    fn on_ack(&mut self, acked: u32, now: Instant);
+
+    /// Called with a new round trip time sample.
+    fn on_rtt(&mut self, rtt: Duration);
+
+    /// Called when a packet was nacked by the receiver.
+    fn on_loss(&mut self, now: Instant);
+
+    /// Called when all packets sent before the last loss were acknowledged.
+    fn on_recovered(&mut self, _now: Instant) {}
+
+    /// Called when a retransmission timer expired.
+    fn on_timeout(&mut self, now: Instant);
+
+    /// Called when the receiver echoed a congestion experienced mark. By
+    /// default it is treated like a packet loss (RFC 3168).
+    fn on_ecn(&mut self, now: Instant) {
+        self.on_loss(now);
+    }
+}
+
+/// Clones a boxed congestion controller. It is implemented for all
+/// controllers that implement `Clone`.
+pub trait CloneCongestionController {
+    /// Returns a copy of the controller.
+    fn clone_box(&self) -> Box<dyn CongestionController>;
+}
+
+impl<T: CongestionController + Clone + 'static> CloneCongestionController for T {
+    fn clone_box(&self) -> Box<dyn CongestionController> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn CongestionController> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// NewReno congestion control (RFC 6582).
+///
+/// After a loss the window is halved and stays constant until the recovery
+/// ends. Partial acks, which acknowledge some but not all packets sent
+/// before the loss, don't grow the window. Packets that left the network
+/// are not counted as in flight, which replaces the window inflation and
+/// deflation of TCP.
+#[derive(Clone)]
+pub(crate) struct NewReno {
+    /// Congestion window.
+    cwnd: u32,
+    /// Slow start threshold.
+    ssthresh: u32,
+    /// Packets acknowledged since the window was last increased during
+    /// congestion avoidance.
+    acked: u32,
+    /// Fast recovery after a loss.
+    recovery: bool,
+}
+
+impl NewReno {
+    pub fn new() -> Self {
+        Self {
+            cwnd: INITIAL_WINDOW,
+            ssthresh: core::u32::MAX,
+            acked: 0,
+            recovery: false,
+        }
+    }
+}
+
+impl CongestionController for NewReno {
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, acked: u32, _now: Instant) {
+        if self.recovery {
+            // Partial ack.
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            // Slow start.
+            self.cwnd = self.cwnd.saturating_add(acked);
+            return;
+        }
+        // Congestion avoidance, increase by one packet per window.
+        self.acked = self.acked.saturating_add(acked);
+        while self.acked >= self.cwnd {
+            self.acked -= self.cwnd;
+            self.cwnd = self.cwnd.saturating_add(1);
+        }
+    }
+
+    fn on_rtt(&mut self, _rtt: Duration) {}
+
+    fn on_loss(&mut self, _now: Instant) {
+        self.ssthresh = (self.cwnd / 2).max(MIN_WINDOW);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+        self.recovery = true;
+    }
+
+    fn on_recovered(&mut self, _now: Instant) {
+        if self.recovery {
+            self.recovery = false;
+            self.cwnd = self.ssthresh;
+        }
+    }
+
+    fn on_timeout(&mut self, _now: Instant) {
+        self.ssthresh = (self.cwnd / 2).max(MIN_WINDOW);
+        self.cwnd = 1;
+        self.acked = 0;
+        // Slow start instead of fast recovery.
+        self.recovery = false;
+    }
+}
+
+/// Scaling constant of the cubic function.
+const CUBIC_C: f64 = 0.4;
+/// Multiplicative window decrease factor.
+const CUBIC_BETA: f64 = 0.7;
+
+/// CUBIC congestion control.
+#[derive(Clone)]
+pub(crate) struct Cubic {
+    /// Congestion window.
+    cwnd: f64,
+    /// Slow start threshold.
+    ssthresh: f64,
+    /// Window before the last reduction.
+    w_max: f64,
+    /// Estimated window of a NewReno flow, used to stay TCP friendly.
+    w_est: f64,
+    /// Time it takes to grow the window back to `w_max`.
+    k: f64,
+    /// Start of the current congestion avoidance epoch.
+    epoch: Option<Instant>,
+    /// Minimum round trip time observed.
+    min_rtt: Option<Duration>,
+}
+
+impl Cubic {
+    pub fn new() -> Self {
+        Self {
+            cwnd: INITIAL_WINDOW as f64,
+            ssthresh: core::f64::MAX,
+            w_max: 0.0,
+            w_est: 0.0,
+            k: 0.0,
+            epoch: None,
+            min_rtt: None,
+        }
+    }
+
+    /// Reduces the window and returns the new slow start threshold.
+    fn reduce(&mut self) -> f64 {
+        // Fast convergence, release bandwidth for new flows.
+        self.w_max = if self.cwnd < self.w_max {
+            self.cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            self.cwnd
+        };
+        self.epoch = None;
+        (self.cwnd * CUBIC_BETA).max(MIN_WINDOW as f64)
+    }
+}
+
+impl CongestionController for Cubic {
+    fn window(&self) -> u32 {
+        self.cwnd as u32
+    }
+
+    fn on_ack(&mut self, acked: u32, now: Instant) {
+        let acked = acked as f64;
+        if self.cwnd < self.ssthresh {
+            // Slow start.
+            self.cwnd += acked;
+            return;
+        }
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => {
+                if self.cwnd < self.w_max {
+                    self.k = (self.w_max * (1.0 - CUBIC_BETA) / CUBIC_C).cbrt();
+                } else {
+                    self.k = 0.0;
+                    self.w_max = self.cwnd;
+                }
+                self.w_est = self.cwnd;
+                self.epoch = Some(now);
+                now
+            }
+        };
+        let rtt = self.min_rtt.unwrap_or_default();
+        let t = (now - epoch + rtt).as_secs_f64();
+        let target = CUBIC_C * (t - self.k).powi(3) + self.w_max;
+        self.w_est += 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * acked / self.cwnd;
+        if target > self.cwnd {
+            // Grow by at most half the window per ack.
+            let inc = ((target - self.cwnd) / self.cwnd).min(0.5);
+            self.cwnd += inc * acked;
+        }
+        if self.w_est > self.cwnd {
+            self.cwnd = self.w_est;
+        }
+    }
+
+    fn on_rtt(&mut self, rtt: Duration) {
+        self.min_rtt = Some(match self.min_rtt {
+            Some(min_rtt) => min_rtt.min(rtt),
+            None => rtt,
+        });
+    }
+
+    fn on_loss(&mut self, _now: Instant) {
+        self.ssthresh = self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_timeout(&mut self, _now: Instant) {
+        self.ssthresh = self.reduce();
+        self.cwnd = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_reno() {
+        let now = Instant::now();
+        let mut cc = NewReno::new();
+        cc.on_ack(10, now);
+        assert_eq!(cc.window(), 20);
+        cc.on_loss(now);
+        assert_eq!(cc.window(), 10);
+        cc.on_recovered(now);
+        cc.on_ack(5, now);
+        assert_eq!(cc.window(), 10);
+        cc.on_ack(5, now);
+        assert_eq!(cc.window(), 11);
+        cc.on_timeout(now);
+        assert_eq!(cc.window(), 1);
+        cc.on_ack(4, now);
+        assert_eq!(cc.window(), 5);
+        cc.on_ack(1, now);
+        assert_eq!(cc.window(), 5);
+    }
+
+    #[test]
+    fn test_new_reno_recovery() {
+        let now = Instant::now();
+        let mut cc = NewReno::new();
+        cc.on_ack(10, now);
+        cc.on_loss(now);
+        assert_eq!(cc.window(), 10);
+        // Partial acks don't grow the window.
+        cc.on_ack(5, now);
+        cc.on_ack(20, now);
+        assert_eq!(cc.window(), 10);
+        // Congestion avoidance after the recovery.
+        cc.on_recovered(now);
+        assert_eq!(cc.window(), 10);
+        cc.on_ack(10, now);
+        assert_eq!(cc.window(), 11);
+        // A timeout ends the recovery with slow start.
+        cc.on_loss(now);
+        cc.on_timeout(now);
+        cc.on_ack(2, now);
+        assert_eq!(cc.window(), 3);
+        cc.on_recovered(now);
+        assert_eq!(cc.window(), 3);
+    }
+
+    #[test]
+    fn test_cubic() {
+        let now = Instant::now();
+        let mut cc = Cubic::new();
+        cc.on_ack(10, now);
+        assert_eq!(cc.window(), 20);
+        cc.on_loss(now);
+        assert_eq!(cc.window(), 14);
+        cc.on_rtt(Duration::from_millis(100));
+        // Concave region, grows back towards the previous maximum.
+        cc.on_ack(14, now);
+        let window = cc.window();
+        assert!(window >= 14 && window < 20);
+        // Convex region, probes beyond the previous maximum.
+        let later = now + Duration::from_secs(5);
+        for _ in 0..10 {
+            cc.on_ack(cc.window(), later);
+        }
+        assert!(cc.window() > 20);
+        cc.on_timeout(later);
+        assert_eq!(cc.window(), 1);
+    }
+}
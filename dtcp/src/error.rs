@@ -0,0 +1,43 @@
+//! Defines the DTCP errors.
+use std::io::{Error, ErrorKind};
+
+/// Error sending a packet.
+///
+/// It is returned wrapped in an `std::io::Error` and can be recovered with
+/// `get_ref` and `downcast_ref`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SendError {
+    /// Max closed window queue length exceeded is used to notify an upper
+    /// layer that it should throttle it's sending rate.
+    MaxClosedWindowQueue,
+    /// Sequence numbers never wrap. A connection that used up all sequence
+    /// numbers can't continue.
+    SeqNumExhausted,
+    /// The channel was shut down for writing.
+    Shutdown,
+    /// The sender inactivity timer expired and discarded packets that were
+    /// not delivered, for example because the receiver kept it's window
+    /// closed.
+    Expired,
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SendError::MaxClosedWindowQueue => {
+                write!(f, "max closed window queue length exceeded")
+            }
+            SendError::SeqNumExhausted => write!(f, "sequence numbers exhausted"),
+            SendError::Shutdown => write!(f, "channel shut down for writing"),
+            SendError::Expired => write!(f, "undelivered packets expired"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<SendError> for Error {
+    fn from(err: SendError) -> Self {
+        Error::new(ErrorKind::Other, err)
+    }
+}
@@ -0,0 +1,395 @@
+//! Flow control.
+use crate::congestion::CongestionController;
+use crate::error::SendError;
+use crate::packet::{DtcpPacket, FlowInfo};
+use crate::rate::{Rate, RateFlowControl};
+use crate::window::WindowFlowControl;
+use channel::BasePacket;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Combines window and rate based flow control with congestion control.
+///
+/// Packets that are not in the send window are queued until the window
+/// opens.
+pub(crate) struct FlowControl<P> {
+    window: WindowFlowControl,
+    rate: RateFlowControl,
+    congestion: Box<dyn CongestionController>,
+    /// Sequence number following the last packet sent.
+    next_seq_num: u64,
+    /// Losses of packets sent before the last window reduction are part of
+    /// the same congestion event.
+    recover: Option<u64>,
+    /// Signal the receiver that the window was reduced in response to an
+    /// ECN echo.
+    cwr: bool,
+    /// Queue of packets ready to be sent once the window opens.
+    closed_window_queue: VecDeque<DtcpPacket<P>>,
+    /// Maximum number of packets queued because the window is closed.
+    max_closed_window_queue: usize,
+    /// Window timer is used to probe the receiver while the window is closed
+    /// in case a window update was lost.
+    timer: Option<Instant>,
+    /// Interval of the window timer.
+    interval: Duration,
+    /// Largest control sequence number received.
+    control_seq_num: Option<u64>,
+}
+
+impl<P: BasePacket> FlowControl<P> {
+    pub fn new(
+        window: u16,
+        rate: Option<Rate>,
+        congestion: Box<dyn CongestionController>,
+        max_closed_window_queue: usize,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            window: WindowFlowControl::new(window),
+            rate: RateFlowControl::new(rate),
+            congestion,
+            next_seq_num: 0,
+            recover: None,
+            cwr: false,
+            closed_window_queue: VecDeque::new(),
+            max_closed_window_queue,
+            timer: None,
+            interval,
+            control_seq_num: None,
+        }
+    }
+
+    /// Returns an error if no more packets can be queued.
+    pub fn check(&self) -> Result<(), SendError> {
+        if self.closed_window_queue.len() >= self.max_closed_window_queue {
+            return Err(SendError::MaxClosedWindowQueue);
+        }
+        Ok(())
+    }
+
+    fn in_send_window(&mut self, seq_num: u64, in_flight: usize, now: Instant) -> bool {
+        self.window.window_open(seq_num)
+            && in_flight < self.congestion.window() as usize
+            && self.rate.window_open(now)
+    }
+
+    /// Registers a sent packet.
+    fn register(&mut self, packet: &mut DtcpPacket<P>) {
+        self.rate.register();
+        if self.cwr {
+            self.cwr = false;
+            packet.set_cwr();
+        }
+        self.next_seq_num = self.next_seq_num.max(packet.seq_num() + 1);
+    }
+
+    /// Returns the packet if it is in the send window, otherwise the packet
+    /// is queued until the window opens.
+    ///
+    /// `in_flight` is the number of sent packets that have not been
+    /// acknowledged.
+    pub fn send(
+        &mut self,
+        mut packet: DtcpPacket<P>,
+        in_flight: usize,
+        now: Instant,
+    ) -> Option<DtcpPacket<P>> {
+        if self.closed_window_queue.is_empty()
+            && self.in_send_window(packet.seq_num(), in_flight, now)
+        {
+            self.register(&mut packet);
+            return Some(packet);
+        }
+        self.closed_window_queue.push_back(packet);
+        self.update_timer(now);
+        None
+    }
+
+    /// Returns the queued packets that are in the send window.
+    pub fn ready(&mut self, in_flight: usize, now: Instant) -> Vec<DtcpPacket<P>> {
+        let mut packets = Vec::new();
+        loop {
+            let seq_num = match self.closed_window_queue.front() {
+                Some(packet) => packet.seq_num(),
+                None => break,
+            };
+            if !self.in_send_window(seq_num, in_flight + packets.len(), now) {
+                break;
+            }
+            let mut packet = self.closed_window_queue.pop_front().unwrap();
+            self.register(&mut packet);
+            packets.push(packet);
+        }
+        self.update_timer(now);
+        packets
+    }
+
+    /// Starts the window timer when the window is closed and stops it when
+    /// the window is open.
+    fn update_timer(&mut self, now: Instant) {
+        let closed = match self.closed_window_queue.front() {
+            Some(packet) => !self.window.window_open(packet.seq_num()),
+            None => false,
+        };
+        if !closed {
+            self.timer = None;
+        } else if self.timer.is_none() {
+            self.timer = Some(now + self.interval);
+        }
+    }
+
+    /// Updates the send window with the flow control information received
+    /// in a control PDU and returns the packets that can be sent.
+    pub fn update(
+        &mut self,
+        seq_num: u64,
+        flow: &FlowInfo,
+        in_flight: usize,
+        now: Instant,
+    ) -> Vec<DtcpPacket<P>> {
+        self.window.update(flow.rwe);
+        let stale = match self.control_seq_num {
+            Some(last) => seq_num < last,
+            None => false,
+        };
+        if !stale {
+            self.control_seq_num = Some(seq_num);
+            self.rate.update(flow.rate, flow.time_unit);
+        }
+        self.ready(in_flight, now)
+    }
+
+    /// Registers acknowledged packets and a round trip time sample with the
+    /// congestion controller. `lwe` is the left window edge of the ack, the
+    /// recovery from a congestion event ends once it passes the packets
+    /// sent before the event.
+    pub fn on_ack(&mut self, acked: u32, rtt: Option<Duration>, lwe: u64, now: Instant) {
+        if let Some(rtt) = rtt {
+            self.congestion.on_rtt(rtt);
+        }
+        if let Some(recover) = self.recover {
+            if lwe >= recover {
+                self.recover = None;
+                self.congestion.on_recovered(now);
+            }
+        }
+        if acked > 0 {
+            self.congestion.on_ack(acked, now);
+        }
+    }
+
+    /// Returns `true` if the loss of a packet starts a new congestion event.
+    fn new_congestion_event(&mut self, seq_num: u64) -> bool {
+        if let Some(recover) = self.recover {
+            if seq_num < recover {
+                return false;
+            }
+        }
+        self.recover = Some(self.next_seq_num);
+        true
+    }
+
+    /// Registers a nacked packet with the congestion controller.
+    pub fn on_loss(&mut self, seq_num: u64, now: Instant) {
+        if self.new_congestion_event(seq_num) {
+            self.congestion.on_loss(now);
+        }
+    }
+
+    /// Registers a packet whose retransmission timer expired with the
+    /// congestion controller.
+    pub fn on_timeout(&mut self, seq_num: u64, now: Instant) {
+        if self.new_congestion_event(seq_num) {
+            self.congestion.on_timeout(now);
+        }
+    }
+
+    /// Registers an ECN echo with the congestion controller. `seq_num` is
+    /// the left window edge of the ack carrying the echo.
+    pub fn on_ecn(&mut self, seq_num: u64, now: Instant) {
+        if self.new_congestion_event(seq_num) {
+            self.congestion.on_ecn(now);
+            self.cwr = true;
+        }
+    }
+
+    /// Returns `true` if there are no packets waiting for the window to open.
+    pub fn is_empty(&self) -> bool {
+        self.closed_window_queue.is_empty()
+    }
+
+    /// Returns when the sending rate timer expires if the rate for the
+    /// current time unit is fulfilled.
+    pub fn rate_deadline(&self, now: Instant) -> Option<Instant> {
+        self.rate.deadline(now)
+    }
+
+    /// Returns when the next flow control timer expires.
+    pub fn deadline(&self, now: Instant) -> Option<Instant> {
+        let rate = if self.closed_window_queue.is_empty() {
+            None
+        } else {
+            self.rate.deadline(now)
+        };
+        self.timer.into_iter().chain(rate).min()
+    }
+
+    /// Returns `true` and restarts the window timer if it expired.
+    pub fn probe(&mut self, now: Instant) -> bool {
+        match self.timer {
+            Some(deadline) if deadline <= now => {
+                self.timer = Some(now + self.interval);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Discards the queued packets when the sender inactivity timer expires.
+    pub fn clear(&mut self) {
+        self.closed_window_queue.clear();
+        self.timer = None;
+    }
+
+    /// Moves the receive window to the first packet of a new connection.
+    pub fn reset_recv_window(&mut self, seq_num: u64) {
+        self.window.reset(seq_num);
+    }
+
+    /// Returns `true` if the packet is in the receive window.
+    pub fn in_recv_window(&self, seq_num: u64) -> bool {
+        self.window.in_recv_window(seq_num)
+    }
+
+    /// Registers a packet delivered to the application. Returns `true` if
+    /// the peer should be sent a window update.
+    pub fn deliver(&mut self, seq_num: u64) -> bool {
+        self.window.deliver(seq_num)
+    }
+
+    /// Returns the flow control information to send to the peer.
+    pub fn advertise(&mut self) -> FlowInfo {
+        let (rate, time_unit) = self.rate.advertise();
+        FlowInfo {
+            rwe: self.window.advertise(),
+            rate,
+            time_unit,
+        }
+    }
+
+    /// Sets the receiving rate advertised to the peer.
+    pub fn set_rate(&mut self, rate: Option<Rate>) {
+        self.rate.set_advertised(rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::congestion::CongestionControl;
+    use crate::packet::DtcpType;
+    use bytes::BytesMut;
+
+    fn packet(seq_num: u64) -> DtcpPacket<BytesMut> {
+        let mut packet = DtcpPacket::from("ping");
+        packet.set_ty(DtcpType::Transfer { drf: false });
+        packet.set_seq_num(seq_num);
+        packet
+    }
+
+    #[test]
+    fn test_closed_window_queue() {
+        let now = Instant::now();
+        let cc = CongestionControl::NewReno.build();
+        let mut flow = FlowControl::new(2, None, cc, 1, Duration::from_millis(10));
+        assert!(flow.send(packet(0), 0, now).is_some());
+        assert!(flow.send(packet(1), 1, now).is_some());
+        assert!(flow.check().is_ok());
+        assert!(flow.send(packet(2), 2, now).is_none());
+        assert_eq!(flow.check(), Err(SendError::MaxClosedWindowQueue));
+        assert!(flow.deadline(now).is_some());
+
+        let info = FlowInfo {
+            rwe: 3,
+            rate: 0,
+            time_unit: 0,
+        };
+        let packets = flow.update(0, &info, 0, now);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].seq_num(), 2);
+        assert!(flow.is_empty());
+        assert!(flow.deadline(now).is_none());
+    }
+
+    #[test]
+    fn test_rate_queue() {
+        let now = Instant::now();
+        let rate = Rate::new(1, Duration::from_millis(10));
+        let cc = CongestionControl::NewReno.build();
+        let mut flow = FlowControl::new(2, rate, cc, 1, Duration::from_millis(10));
+        assert!(flow.send(packet(0), 0, now).is_some());
+        assert!(flow.send(packet(1), 1, now).is_none());
+        let deadline = flow.deadline(now).unwrap();
+        assert!(flow.ready(1, now).is_empty());
+        assert_eq!(flow.ready(1, deadline).len(), 1);
+    }
+
+    #[test]
+    fn test_congestion_window() {
+        let now = Instant::now();
+        let cc = CongestionControl::NewReno.build();
+        let mut flow = FlowControl::new(64, None, cc, 64, Duration::from_millis(10));
+        for i in 0..10 {
+            assert!(flow.send(packet(i), i as usize, now).is_some());
+        }
+        assert!(flow.send(packet(10), 10, now).is_none());
+        // Packets 0..10 are part of the same congestion event.
+        flow.on_timeout(0, now);
+        flow.on_timeout(5, now);
+        assert!(flow.ready(1, now).is_empty());
+        flow.on_ack(1, None, 1, now);
+        assert_eq!(flow.ready(1, now).len(), 1);
+    }
+
+    #[test]
+    fn test_recovery() {
+        let now = Instant::now();
+        let cc = CongestionControl::NewReno.build();
+        let mut flow = FlowControl::new(64, None, cc, 64, Duration::from_millis(10));
+        for i in 0..10 {
+            assert!(flow.send(packet(i), i as usize, now).is_some());
+        }
+        assert!(flow.send(packet(10), 10, now).is_none());
+        // The window is halved to 5.
+        flow.on_loss(0, now);
+        flow.on_ack(5, None, 5, now);
+        // Partial acks don't grow the window.
+        assert!(flow.ready(5, now).is_empty());
+        // Losses of packets sent before the event are part of it.
+        flow.on_loss(7, now);
+        assert_eq!(flow.ready(4, now).len(), 1);
+        assert!(flow.send(packet(11), 5, now).is_none());
+        // The recovery ends when all packets sent before the loss are acked.
+        flow.on_ack(5, None, 10, now);
+        assert!(flow.recover.is_none());
+        assert_eq!(flow.ready(5, now).len(), 1);
+    }
+
+    #[test]
+    fn test_ecn() {
+        let now = Instant::now();
+        let cc = CongestionControl::NewReno.build();
+        let mut flow = FlowControl::new(64, None, cc, 64, Duration::from_millis(10));
+        for i in 0..4 {
+            assert!(!flow.send(packet(i), 0, now).unwrap().cwr());
+        }
+        flow.on_ecn(0, now);
+        // Marks of packets sent before the reduction are ignored.
+        flow.on_ecn(2, now);
+        assert!(flow.send(packet(4), 0, now).unwrap().cwr());
+        assert!(!flow.send(packet(5), 0, now).unwrap().cwr());
+        flow.on_ecn(4, now);
+        assert!(flow.send(packet(6), 0, now).unwrap().cwr());
+    }
+}
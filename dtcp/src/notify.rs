@@ -0,0 +1,156 @@
+//! Coordinates the tasks polling a dtcp channel. It is also used by upper
+//! layers that share a channel or socket between tasks.
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Ensures that only a single task polls the underlying channel and wakes
+/// the other tasks when it made progress.
+pub struct Notify {
+    polling: AtomicBool,
+    state: Mutex<State>,
+}
+
+struct State {
+    generation: u64,
+    wakers: Vec<Waker>,
+}
+
+impl Notify {
+    /// Creates a new `Notify`.
+    pub fn new() -> Self {
+        Self {
+            polling: AtomicBool::new(false),
+            state: Mutex::new(State {
+                generation: 0,
+                wakers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Returns a guard if no other task is polling the underlying channel.
+    /// Waiting tasks are notified when the guard is dropped.
+    pub fn try_lock(&self) -> Option<NotifyGuard<'_>> {
+        if self.polling.swap(true, Ordering::SeqCst) {
+            None
+        } else {
+            Some(NotifyGuard(self))
+        }
+    }
+
+    /// Returns a future that resolves on the next notification.
+    ///
+    /// It must be created before checking the state it waits on, otherwise
+    /// a notification can be missed.
+    pub fn notified(&self) -> Notified<'_> {
+        let generation = self.state.lock().unwrap().generation;
+        Notified {
+            notify: self,
+            generation,
+        }
+    }
+
+    /// Wakes all waiting tasks.
+    pub fn notify(&self) {
+        let wakers = {
+            let mut state = self.state.lock().unwrap();
+            state.generation += 1;
+            state.wakers.drain(..).collect::<Vec<_>>()
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Guard of the task polling the underlying channel.
+pub struct NotifyGuard<'a>(&'a Notify);
+
+impl<'a> Drop for NotifyGuard<'a> {
+    fn drop(&mut self) {
+        self.0.polling.store(false, Ordering::SeqCst);
+        self.0.notify();
+    }
+}
+
+/// Future returned by `notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    generation: u64,
+}
+
+impl<'a> Future for Notified<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.notify.state.lock().unwrap();
+        if state.generation != self.generation {
+            return Poll::Ready(());
+        }
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// Future returned by `until_notified`.
+pub(crate) struct UntilNotified<'a, F> {
+    notified: Notified<'a>,
+    future: F,
+}
+
+impl<'a, F: Future + Unpin> Future for UntilNotified<'a, F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = Pin::new(&mut self.future).poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        if let Poll::Ready(()) = Pin::new(&mut self.notified).poll(cx) {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+/// Resolves to `None` if a notification arrived before the future
+/// completed.
+pub(crate) fn until_notified<F: Future + Unpin>(
+    notified: Notified<'_>,
+    future: F,
+) -> UntilNotified<'_, F> {
+    UntilNotified { notified, future }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use futures_timer::Delay;
+    use std::time::Duration;
+
+    #[test]
+    fn test_notify() {
+        let notify = Notify::new();
+        let guard = notify.try_lock().unwrap();
+        assert!(notify.try_lock().is_none());
+        let notified = notify.notified();
+        drop(guard);
+        task::block_on(notified);
+        assert!(notify.try_lock().is_some());
+
+        let notified = notify.notified();
+        notify.notify();
+        let delay = Delay::new(Duration::from_secs(60));
+        assert_eq!(task::block_on(until_notified(notified, delay)), None);
+    }
+}
@@ -17,22 +17,198 @@ pub enum DtcpType {
     Control,
 }
 
+/// Flow control information present.
+const FCI: u8 = 0b1000;
+/// Ack/nack information present.
+const ACKI: u8 = 0b0100;
+/// Selective ack/nack information present.
+const SEL_ACK: u8 = 0b0010;
+/// Echo of a congestion experienced mark.
+const ECE: u8 = 0b0001;
+/// Congestion window reduced in response to an ECN echo.
+const CWR: u8 = 0b0010;
+/// Last data PDU, the sender shut down writing.
+const FIN: u8 = 0b0100;
+
+/// Ack/nack information of a control PDU.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct AckInfo {
+    /// Left window edge. All packets with a smaller sequence number have been
+    /// received.
+    pub lwe: u64,
+    /// One past the largest sequence number received.
+    pub high: u64,
+    /// Ranges `[start, end)` of missing packets between `lwe` and `high`.
+    pub nacks: Vec<(u64, u64)>,
+}
+
+impl AckInfo {
+    /// Returns `true` if the packet is known to have been received.
+    pub fn is_acked(&self, seq_num: u64) -> bool {
+        seq_num < self.lwe || (seq_num < self.high && !self.is_nacked(seq_num))
+    }
+
+    /// Returns `true` if the packet is known to be missing.
+    pub fn is_nacked(&self, seq_num: u64) -> bool {
+        self.nacks
+            .iter()
+            .any(|&(start, end)| start <= seq_num && seq_num < end)
+    }
+}
+
+/// Flow control information of a control PDU.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct FlowInfo {
+    /// Right window edge. Largest sequence number the receiver accepts.
+    pub rwe: u64,
+    /// Number of PDUs the receiver accepts per time unit, `0` disables rate
+    /// based flow control.
+    pub rate: u32,
+    /// Time unit in milliseconds.
+    pub time_unit: u32,
+}
+
+/// Body of a control PDU.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct ControlPdu {
+    pub ack: Option<AckInfo>,
+    pub flow: Option<FlowInfo>,
+    /// Set until the sender signals that it reduced it's congestion window.
+    pub ece: bool,
+}
+
+impl ControlPdu {
+    fn invalid() -> Error {
+        Error::new(ErrorKind::Other, "invalid dtcp control packet")
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if let Some(ack) = &self.ack {
+            flags |= ACKI;
+            if ack.high != ack.lwe {
+                flags |= SEL_ACK;
+            }
+        }
+        if self.flow.is_some() {
+            flags |= FCI;
+        }
+        if self.ece {
+            flags |= ECE;
+        }
+        flags
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let flags = self.flags();
+        if let Some(ack) = &self.ack {
+            bytes.put_u64_be(ack.lwe);
+            if flags & SEL_ACK > 0 {
+                let max = core::u8::MAX as usize;
+                let (nacks, high) = if ack.nacks.len() > max {
+                    // Packets after the last encoded range must not be
+                    // reported as received.
+                    let nacks = &ack.nacks[..max];
+                    (nacks, nacks[max - 1].1)
+                } else {
+                    (&ack.nacks[..], ack.high)
+                };
+                bytes.put_u64_be(high);
+                bytes.put_u8(nacks.len() as u8);
+                for (start, end) in nacks {
+                    bytes.put_u64_be(*start);
+                    bytes.put_u64_be(*end);
+                }
+            }
+        }
+        if let Some(flow) = &self.flow {
+            bytes.put_u64_be(flow.rwe);
+            bytes.put_u32_be(flow.rate);
+            bytes.put_u32_be(flow.time_unit);
+        }
+        bytes
+    }
+
+    fn take<'a>(bytes: &'a [u8], i: &mut usize, len: usize) -> Result<&'a [u8]> {
+        let i2 = *i + len;
+        if bytes.len() < i2 {
+            return Err(Self::invalid());
+        }
+        let slice = &bytes[*i..i2];
+        *i = i2;
+        Ok(slice)
+    }
+
+    fn from_bytes(flags: u8, bytes: &[u8]) -> Result<Self> {
+        let mut i = 0;
+        let ack = if flags & ACKI > 0 {
+            let lwe = BigEndian::read_u64(Self::take(bytes, &mut i, 8)?);
+            let mut ack = AckInfo {
+                lwe,
+                high: lwe,
+                nacks: Vec::new(),
+            };
+            if flags & SEL_ACK > 0 {
+                ack.high = BigEndian::read_u64(Self::take(bytes, &mut i, 8)?);
+                let len = Self::take(bytes, &mut i, 1)?[0];
+                for _ in 0..len {
+                    let range = Self::take(bytes, &mut i, 16)?;
+                    let start = BigEndian::read_u64(&range[..8]);
+                    let end = BigEndian::read_u64(&range[8..]);
+                    ack.nacks.push((start, end));
+                }
+            }
+            Some(ack)
+        } else {
+            None
+        };
+        let flow = if flags & FCI > 0 {
+            let flow = Self::take(bytes, &mut i, 16)?;
+            Some(FlowInfo {
+                rwe: BigEndian::read_u64(&flow[..8]),
+                rate: BigEndian::read_u32(&flow[8..12]),
+                time_unit: BigEndian::read_u32(&flow[12..]),
+            })
+        } else {
+            None
+        };
+        Ok(Self {
+            ack,
+            flow,
+            ece: flags & ECE > 0,
+        })
+    }
+}
+
 /// DTCP Header:
-///   type: u8
-///   sequence_number: u16
+///   type: u4
+///   flags: u4
+///   sequence_number: u64
+///
+/// Sequence numbers start at zero and never wrap. The flags of a transfer
+/// PDU are `drf`, `cwr` and `fin`. The sequence number of a control PDU is a
+/// separate control sequence number. The body of a control PDU depends on
+/// the flags, `ece` has no body:
+///   acki: left_window_edge: u64
+///   sel_ack: high: u64, len: u8, nack_ranges: [(start: u64, end: u64); len]
+///   fci: right_window_edge: u64, rate: u32, time_unit: u32
 #[derive(Clone)]
 pub struct DtcpPacket<P>(P);
 
+/// Length of the DTCP header.
+const HEADER_LEN: usize = 9;
+
 impl<P: BasePacket> BasePacket for DtcpPacket<P> {
     fn new(payload_len: usize) -> Self {
-        let mut packet = P::new(payload_len + 3);
+        let mut packet = P::new(payload_len + HEADER_LEN);
         packet.put_u8(0);
-        packet.put_u16_be(0);
+        packet.put_u64_be(0);
         Self(packet)
     }
 
     fn check(&self) -> Result<()> {
-        if self.0.payload().len() < 3 {
+        if self.0.payload().len() < HEADER_LEN {
             return Err(Error::new(ErrorKind::Other, "invalid dtcp packet"));
         }
         if self.raw_type() >= 2 {
@@ -42,11 +218,15 @@ impl<P: BasePacket> BasePacket for DtcpPacket<P> {
     }
 
     fn payload(&self) -> &[u8] {
-        &self.0.payload()[3..]
+        &self.0.payload()[HEADER_LEN..]
     }
 
     fn payload_mut(&mut self) -> &mut [u8] {
-        &mut self.0.payload_mut()[3..]
+        &mut self.0.payload_mut()[HEADER_LEN..]
+    }
+
+    fn truncate(&mut self, payload_len: usize) {
+        self.0.truncate(payload_len + HEADER_LEN)
     }
 
     fn debug(&self, ds: &mut std::fmt::DebugStruct) {
@@ -54,6 +234,14 @@ impl<P: BasePacket> BasePacket for DtcpPacket<P> {
         ds.field("type", &self.ty());
         ds.field("seq_num", &self.seq_num());
     }
+
+    fn ecn(&self) -> bool {
+        self.0.ecn()
+    }
+
+    fn set_ecn(&mut self, ecn: bool) {
+        self.0.set_ecn(ecn)
+    }
 }
 
 derive_packet!(DtcpPacket);
@@ -63,8 +251,12 @@ impl<P: BasePacket> DtcpPacket<P> {
         self.0.payload()[0] >> 4
     }
 
+    fn flags(&self) -> u8 {
+        self.0.payload()[0] & 0b1111
+    }
+
     fn flag0(&self) -> bool {
-        self.0.payload()[0] & 0b0001 > 0
+        self.flags() & 0b0001 > 0
     }
 
     pub(crate) fn ty(&self) -> DtcpType {
@@ -91,11 +283,181 @@ impl<P: BasePacket> DtcpPacket<P> {
         self.0.payload_mut()[0] = byte;
     }
 
-    pub(crate) fn seq_num(&self) -> u16 {
-        BigEndian::read_u16(&self.0.payload()[1..3])
+    /// Returns `true` if the sender reduced it's congestion window.
+    pub(crate) fn cwr(&self) -> bool {
+        self.raw_type() == 0 && self.flags() & CWR > 0
+    }
+
+    /// Signals that the congestion window was reduced. Must be called after
+    /// `set_ty`.
+    pub(crate) fn set_cwr(&mut self) {
+        debug_assert_eq!(self.raw_type(), 0);
+        self.0.payload_mut()[0] |= CWR;
+    }
+
+    /// Returns `true` if this is the last data packet of the sender. `recv`
+    /// returns it to signal the end of stream.
+    pub fn fin(&self) -> bool {
+        self.raw_type() == 0 && self.flags() & FIN > 0
+    }
+
+    /// Marks the last data packet. Must be called after `set_ty`.
+    pub(crate) fn set_fin(&mut self) {
+        debug_assert_eq!(self.raw_type(), 0);
+        self.0.payload_mut()[0] |= FIN;
+    }
+
+    pub(crate) fn seq_num(&self) -> u64 {
+        BigEndian::read_u64(&self.0.payload()[1..HEADER_LEN])
+    }
+
+    pub(crate) fn set_seq_num(&mut self, seq_num: u64) {
+        BigEndian::write_u64(&mut self.0.payload_mut()[1..HEADER_LEN], seq_num)
+    }
+
+    /// Creates a control PDU.
+    pub(crate) fn control(seq_num: u64, control: &ControlPdu) -> Self {
+        let body = control.to_bytes();
+        let mut packet = Self::new(body.len());
+        packet.set_ty(DtcpType::Control);
+        packet.0.payload_mut()[0] |= control.flags();
+        packet.set_seq_num(seq_num);
+        packet.put_slice(&body);
+        packet
+    }
+
+    /// Parses the body of a control PDU.
+    pub(crate) fn to_control(&self) -> Result<ControlPdu> {
+        debug_assert_eq!(self.ty(), DtcpType::Control);
+        ControlPdu::from_bytes(self.flags(), self.payload())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    fn check(control: ControlPdu) {
+        let packet = DtcpPacket::<BytesMut>::control(42, &control);
+        assert_eq!(packet.ty(), DtcpType::Control);
+        assert_eq!(packet.seq_num(), 42);
+        assert_eq!(packet.to_control().unwrap(), control);
+    }
+
+    #[test]
+    fn test_control_serde() {
+        let ack = AckInfo {
+            lwe: 3,
+            high: 3,
+            nacks: vec![],
+        };
+        let sel_ack = AckInfo {
+            lwe: 3,
+            high: 9,
+            nacks: vec![(3, 4), (6, 8)],
+        };
+        let flow = FlowInfo {
+            rwe: 20,
+            rate: 100,
+            time_unit: 1000,
+        };
+        check(ControlPdu::default());
+        check(ControlPdu {
+            ack: Some(ack),
+            flow: None,
+            ece: false,
+        });
+        check(ControlPdu {
+            ack: Some(sel_ack.clone()),
+            flow: None,
+            ece: false,
+        });
+        check(ControlPdu {
+            ack: None,
+            flow: Some(flow),
+            ece: false,
+        });
+        check(ControlPdu {
+            ack: Some(sel_ack),
+            flow: Some(flow),
+            ece: false,
+        });
+        check(ControlPdu {
+            ack: None,
+            flow: None,
+            ece: true,
+        });
+    }
+
+    #[test]
+    fn test_control_truncated_nacks() {
+        // Every other packet is missing.
+        let ack = AckInfo {
+            lwe: 1,
+            high: 601,
+            nacks: (0..300).map(|i| (2 * i + 1, 2 * i + 2)).collect(),
+        };
+        let control = ControlPdu {
+            ack: Some(ack),
+            flow: None,
+            ece: false,
+        };
+        let packet = DtcpPacket::<BytesMut>::control(0, &control);
+        let ack = packet.to_control().unwrap().ack.unwrap();
+        assert_eq!(ack.nacks.len(), 255);
+        assert_eq!(ack.high, 510);
+        assert!(ack.is_acked(508));
+        assert!(ack.is_nacked(509));
+        assert!(!ack.is_acked(510));
+        assert!(!ack.is_acked(511));
+        assert!(!ack.is_acked(600));
+    }
+
+    #[test]
+    fn test_cwr() {
+        let mut packet = DtcpPacket::<BytesMut>::from("ping");
+        packet.set_ty(DtcpType::Transfer { drf: true });
+        assert!(!packet.cwr());
+        packet.set_cwr();
+        assert!(packet.cwr());
+        assert_eq!(packet.ty(), DtcpType::Transfer { drf: true });
+    }
+
+    #[test]
+    fn test_fin() {
+        let mut packet = DtcpPacket::<BytesMut>::new(0);
+        packet.set_ty(DtcpType::Transfer { drf: false });
+        assert!(!packet.fin());
+        packet.set_fin();
+        packet.set_cwr();
+        assert!(packet.fin());
+        assert!(packet.cwr());
+        assert_eq!(packet.ty(), DtcpType::Transfer { drf: false });
+    }
+
+    #[test]
+    fn test_ack_info() {
+        let ack = AckInfo {
+            lwe: 0xfffe,
+            high: 0x1_0003,
+            nacks: vec![(0xfffe, 0x1_0000), (0x1_0001, 0x1_0002)],
+        };
+        assert!(ack.is_acked(0xfffd));
+        assert!(!ack.is_acked(0xfffe));
+        assert!(ack.is_nacked(0xffff));
+        assert!(ack.is_acked(0x1_0000));
+        assert!(ack.is_nacked(0x1_0001));
+        assert!(ack.is_acked(0x1_0002));
+        assert!(!ack.is_acked(0x1_0003));
+        assert!(!ack.is_nacked(0x1_0003));
     }
 
-    pub(crate) fn set_seq_num(&mut self, seq_num: u16) {
-        BigEndian::write_u16(&mut self.0.payload_mut()[1..3], seq_num)
+    #[test]
+    fn test_seq_num() {
+        let mut packet = DtcpPacket::<BytesMut>::from("ping");
+        packet.set_seq_num(core::u64::MAX);
+        assert_eq!(packet.seq_num(), core::u64::MAX);
+        assert_eq!(packet.payload(), b"ping");
     }
 }
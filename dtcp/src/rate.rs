@@ -0,0 +1,156 @@
+//! Rate based flow control.
+use std::time::{Duration, Instant};
+
+/// Sending rate in PDUs per time unit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Rate {
+    /// Number of PDUs that can be sent per time unit.
+    pub pdus: u32,
+    /// Unit of time over which the rate is computed.
+    pub time_unit: Duration,
+}
+
+impl Rate {
+    /// Creates a new rate. Returns `None` if the rate is unlimited.
+    pub fn new(pdus: u32, time_unit: Duration) -> Option<Self> {
+        if pdus == 0 || time_unit == Duration::from_millis(0) {
+            return None;
+        }
+        Some(Self { pdus, time_unit })
+    }
+
+    /// Creates a rate from the flow control information of a control PDU.
+    pub fn from_wire(pdus: u32, time_unit: u32) -> Option<Self> {
+        Self::new(pdus, Duration::from_millis(time_unit as u64))
+    }
+
+    /// Returns the flow control information of a control PDU.
+    pub fn to_wire(rate: Option<Self>) -> (u32, u32) {
+        match rate {
+            Some(rate) => {
+                let time_unit = rate.time_unit.as_millis().min(core::u32::MAX as u128);
+                (rate.pdus, time_unit.max(1) as u32)
+            }
+            None => (0, 0),
+        }
+    }
+
+    /// Returns `true` if `self` is slower than `other`.
+    fn is_slower(&self, other: &Self) -> bool {
+        self.pdus as u128 * other.time_unit.as_nanos()
+            < other.pdus as u128 * self.time_unit.as_nanos()
+    }
+}
+
+/// Limits the number of PDUs sent per time unit.
+pub(crate) struct RateFlowControl {
+    /// Local limit of the sending rate.
+    local: Option<Rate>,
+    /// Sending rate requested by the peer.
+    peer: Option<Rate>,
+    /// Receiving rate advertised to the peer.
+    advertised: Option<Rate>,
+    /// Start of the current time unit.
+    start: Instant,
+    /// Number of sent PDUs in the current time unit.
+    pdus_sent_in_time_unit: u32,
+}
+
+impl RateFlowControl {
+    pub fn new(rate: Option<Rate>) -> Self {
+        Self {
+            local: rate,
+            peer: None,
+            advertised: rate,
+            start: Instant::now(),
+            pdus_sent_in_time_unit: 0,
+        }
+    }
+
+    /// Returns the slower of the local limit and the rate requested by the
+    /// peer.
+    pub fn sending_rate(&self) -> Option<Rate> {
+        match (self.local, self.peer) {
+            (Some(local), Some(peer)) if local.is_slower(&peer) => Some(local),
+            (_, Some(peer)) => Some(peer),
+            (local, None) => local,
+        }
+    }
+
+    /// Returns `true` if another PDU can be sent in the current time unit.
+    pub fn window_open(&mut self, now: Instant) -> bool {
+        let rate = match self.sending_rate() {
+            Some(rate) => rate,
+            None => return true,
+        };
+        if now >= self.start + rate.time_unit {
+            self.start = now;
+            self.pdus_sent_in_time_unit = 0;
+        }
+        self.pdus_sent_in_time_unit < rate.pdus
+    }
+
+    /// Registers a sent PDU.
+    pub fn register(&mut self) {
+        self.pdus_sent_in_time_unit = self.pdus_sent_in_time_unit.saturating_add(1);
+    }
+
+    /// Returns when the sending rate timer expires if the rate for the
+    /// current time unit is fulfilled.
+    pub fn deadline(&self, now: Instant) -> Option<Instant> {
+        let rate = self.sending_rate()?;
+        let end = self.start + rate.time_unit;
+        if now < end && self.pdus_sent_in_time_unit >= rate.pdus {
+            Some(end)
+        } else {
+            None
+        }
+    }
+
+    /// Updates the sending rate requested by the peer.
+    pub fn update(&mut self, pdus: u32, time_unit: u32) {
+        self.peer = Rate::from_wire(pdus, time_unit);
+    }
+
+    /// Returns the receiving rate advertised to the peer.
+    pub fn advertise(&self) -> (u32, u32) {
+        Rate::to_wire(self.advertised)
+    }
+
+    /// Sets the receiving rate advertised to the peer.
+    pub fn set_advertised(&mut self, rate: Option<Rate>) {
+        self.advertised = rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate() {
+        let time_unit = Duration::from_millis(10);
+        let mut rate = RateFlowControl::new(Rate::new(2, time_unit));
+        let now = Instant::now();
+        assert!(rate.window_open(now));
+        rate.register();
+        assert!(rate.window_open(now));
+        rate.register();
+        assert!(!rate.window_open(now));
+        let deadline = rate.deadline(now).unwrap();
+        assert!(rate.window_open(deadline));
+        assert!(rate.deadline(deadline).is_none());
+    }
+
+    #[test]
+    fn test_peer_rate() {
+        let mut rate = RateFlowControl::new(Rate::new(2, Duration::from_millis(10)));
+        rate.update(1, 10);
+        assert_eq!(rate.sending_rate(), Rate::new(1, Duration::from_millis(10)));
+        rate.update(10, 10);
+        assert_eq!(rate.sending_rate(), Rate::new(2, Duration::from_millis(10)));
+        rate.update(0, 0);
+        assert_eq!(rate.sending_rate(), Rate::new(2, Duration::from_millis(10)));
+        assert_eq!(rate.advertise(), (2, 10));
+    }
+}
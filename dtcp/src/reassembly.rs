@@ -0,0 +1,192 @@
+//! In-order delivery of received packets.
+use crate::packet::{AckInfo, DtcpPacket};
+use channel::BasePacket;
+use std::collections::VecDeque;
+
+/// Reassembly queue keyed on the sequence number.
+///
+/// Packets are buffered until all packets with a smaller sequence number
+/// of the same data run have been received. Duplicates and stale packets
+/// are dropped.
+pub(crate) struct ReassemblyQueue<P> {
+    /// Left window edge. Next sequence number to deliver, all packets with a
+    /// smaller sequence number have been received. `None` if no connection
+    /// exists.
+    lwe: Option<u64>,
+    /// Packets received above the left window edge, indexed by their offset
+    /// from the left window edge.
+    queue: VecDeque<Option<DtcpPacket<P>>>,
+}
+
+impl<P: BasePacket> ReassemblyQueue<P> {
+    pub fn new() -> Self {
+        Self {
+            lwe: None,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Returns `true` if no connection exists.
+    pub fn is_closed(&self) -> bool {
+        self.lwe.is_none()
+    }
+
+    /// Discards the connection state when the receiver inactivity timer
+    /// expires. Until a packet with the data run flag set is received all
+    /// packets are refused.
+    pub fn reset(&mut self) {
+        self.lwe = None;
+        self.queue.clear();
+    }
+
+    /// Inserts a received packet. Returns `false` if the packet is a
+    /// duplicate or was refused.
+    ///
+    /// A packet with the data run flag set opens a connection or starts a
+    /// new run, discarding the undeliverable packets of the previous run.
+    /// While a connection exists packets from before the left window edge
+    /// are stale and never start a new run.
+    pub fn insert(&mut self, packet: DtcpPacket<P>, drf: bool) -> bool {
+        let seq_num = packet.seq_num();
+        let lwe = match self.lwe {
+            Some(lwe) if seq_num < lwe => return false,
+            Some(lwe) if !drf || seq_num == lwe => lwe,
+            None if !drf => return false,
+            _ => {
+                self.lwe = Some(seq_num);
+                self.queue.clear();
+                seq_num
+            }
+        };
+        let offset = (seq_num - lwe) as usize;
+        if self.queue.len() <= offset {
+            self.queue.resize(offset + 1, None);
+        }
+        if self.queue[offset].is_some() {
+            return false;
+        }
+        self.queue[offset] = Some(packet);
+        true
+    }
+
+    /// Returns the next packet if it has been received.
+    pub fn pop(&mut self) -> Option<DtcpPacket<P>> {
+        if let (Some(lwe), Some(Some(_))) = (self.lwe, self.queue.front()) {
+            self.lwe = Some(lwe + 1);
+            self.queue.pop_front().unwrap()
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if packets with a larger sequence number than a missing
+    /// packet have been received.
+    pub fn has_gaps(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Returns the ack/nack information to send to the peer.
+    pub fn ack_info(&self) -> AckInfo {
+        let lwe = self.lwe.unwrap_or_default();
+        let mut nacks = Vec::new();
+        let mut start = None;
+        for (i, packet) in self.queue.iter().enumerate() {
+            let seq_num = lwe + i as u64;
+            match (start, packet.is_some()) {
+                (None, false) => start = Some(seq_num),
+                (Some(start_seq_num), true) => {
+                    nacks.push((start_seq_num, seq_num));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        AckInfo {
+            lwe,
+            high: lwe + self.queue.len() as u64,
+            nacks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DtcpType;
+    use bytes::BytesMut;
+
+    fn packet(seq_num: u64) -> DtcpPacket<BytesMut> {
+        let mut packet = DtcpPacket::from(&[seq_num as u8][..]);
+        packet.set_ty(DtcpType::Transfer { drf: false });
+        packet.set_seq_num(seq_num);
+        packet
+    }
+
+    fn drain(queue: &mut ReassemblyQueue<BytesMut>) -> Vec<u64> {
+        let mut seq_nums = Vec::new();
+        while let Some(packet) = queue.pop() {
+            seq_nums.push(packet.seq_num());
+        }
+        seq_nums
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(packet(0), true));
+        assert!(!queue.insert(packet(0), false));
+        assert!(queue.insert(packet(2), false));
+        assert!(queue.insert(packet(5), false));
+        assert_eq!(drain(&mut queue), vec![0]);
+        assert!(!queue.insert(packet(0), false));
+
+        let ack = queue.ack_info();
+        assert_eq!(ack.lwe, 1);
+        assert_eq!(ack.high, 6);
+        assert_eq!(ack.nacks, vec![(1, 2), (3, 5)]);
+
+        assert!(queue.insert(packet(1), false));
+        assert_eq!(drain(&mut queue), vec![1, 2]);
+        let ack = queue.ack_info();
+        assert_eq!(ack.lwe, 3);
+        assert_eq!(ack.nacks, vec![(3, 5)]);
+    }
+
+    #[test]
+    fn test_data_run() {
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.insert(packet(0), true));
+        assert!(queue.insert(packet(2), false));
+        assert_eq!(drain(&mut queue), vec![0]);
+
+        // Packet 1 was lost and the sender started a new run.
+        assert!(queue.insert(packet(4), true));
+        assert!(!queue.insert(packet(3), false));
+        assert!(queue.insert(packet(5), false));
+        assert_eq!(drain(&mut queue), vec![4, 5]);
+
+        // Duplicate of the first packet of the run.
+        assert!(!queue.insert(packet(4), true));
+        // Stale packet of a previous run.
+        assert!(!queue.insert(packet(2), true));
+        assert!(queue.insert(packet(6), false));
+        assert_eq!(drain(&mut queue), vec![6]);
+    }
+
+    #[test]
+    fn test_inactivity() {
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.is_closed());
+        assert!(!queue.insert(packet(3), false));
+        assert!(queue.insert(packet(3), true));
+        assert_eq!(drain(&mut queue), vec![3]);
+        assert!(!queue.is_closed());
+
+        // Receiver inactivity timer expired.
+        queue.reset();
+        assert!(queue.is_closed());
+        assert!(!queue.insert(packet(4), false));
+        assert!(queue.insert(packet(0), true));
+        assert_eq!(drain(&mut queue), vec![0]);
+    }
+}
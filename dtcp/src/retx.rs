@@ -0,0 +1,198 @@
+//! Retransmission control.
+use crate::packet::{AckInfo, DtcpPacket};
+use crate::rtt::RttEstimator;
+use channel::BasePacket;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+
+struct Entry<P> {
+    packet: DtcpPacket<P>,
+    /// Time the packet was first sent.
+    sent: Instant,
+    deadline: Instant,
+    retries: u8,
+    /// Packet was retransmitted due to a nack since the timer was started.
+    nacked: bool,
+}
+
+/// Result of processing an ack.
+pub(crate) struct Acked<P> {
+    /// Number of packets that were acknowledged.
+    pub acked: u32,
+    /// Round trip time sample. Retransmitted packets are ambiguous and are
+    /// not sampled (Karn's algorithm).
+    pub rtt: Option<Duration>,
+    /// Nacked packets that need to be retransmitted.
+    pub nacked: Vec<DtcpPacket<P>>,
+}
+
+/// Queue of sent packets that have not yet been acknowledged.
+pub(crate) struct RetransmissionQueue<P> {
+    queue: VecDeque<Entry<P>>,
+    /// Estimates the time to wait for an ack before retransmitting a packet.
+    rtt: RttEstimator,
+    /// Maximum number of retransmission attempts.
+    max_retries: u8,
+}
+
+impl<P: BasePacket> RetransmissionQueue<P> {
+    /// Creates a new retransmission queue. The retransmission timeout never
+    /// exceeds `max_timeout`, which should be set to 2MPL + A.
+    pub fn new(ack: Duration, max_timeout: Duration, max_retries: u8) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            rtt: RttEstimator::new(ack, max_timeout),
+            max_retries,
+        }
+    }
+
+    /// Registers a packet for potential retransmission.
+    pub fn register(&mut self, packet: DtcpPacket<P>) {
+        let now = Instant::now();
+        self.queue.push_back(Entry {
+            packet,
+            sent: now,
+            deadline: now + self.rtt.rto(),
+            retries: 0,
+            nacked: false,
+        });
+    }
+
+    /// Removes acknowledged packets from the queue, updates the rtt estimate
+    /// and returns the nacked packets that need to be retransmitted.
+    ///
+    /// A nacked packet is retransmitted at most once per retransmission
+    /// timeout.
+    pub fn ack(&mut self, ack: &AckInfo, now: Instant) -> Acked<P> {
+        let mut acked = 0;
+        let mut rtt = None;
+        self.queue.retain(|entry| {
+            if !ack.is_acked(entry.packet.seq_num()) {
+                return true;
+            }
+            acked += 1;
+            if entry.retries == 0 && !entry.nacked {
+                rtt = Some(now - entry.sent);
+            }
+            false
+        });
+        if let Some(rtt) = rtt {
+            self.rtt.update(rtt);
+        }
+        let mut nacked = Vec::new();
+        for entry in self.queue.iter_mut() {
+            if !entry.nacked && ack.is_nacked(entry.packet.seq_num()) {
+                entry.nacked = true;
+                nacked.push(entry.packet.clone());
+            }
+        }
+        Acked { acked, rtt, nacked }
+    }
+
+    /// Returns the smoothed round trip time.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.rtt()
+    }
+
+    /// Returns the A-timer interval.
+    pub fn a_timer(&self) -> Duration {
+        self.rtt.a_timer()
+    }
+
+    /// Returns the number of packets in flight.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Discards all packets when the sender inactivity timer expires.
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    /// Returns `true` if all packets have been acknowledged.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Returns when the next retransmission timer expires.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.queue.iter().map(|entry| entry.deadline).min()
+    }
+
+    /// Returns the packets whose retransmission timer expired and restarts
+    /// their timers with an exponential backoff.
+    ///
+    /// If a packet has not been acknowledged after `max_retries`
+    /// retransmissions the queue is discarded and an error is returned.
+    pub fn expired(&mut self, now: Instant) -> Result<Vec<DtcpPacket<P>>> {
+        let mut packets = Vec::new();
+        let mut failed = false;
+        for entry in self.queue.iter_mut() {
+            if entry.deadline > now {
+                continue;
+            }
+            if entry.retries >= self.max_retries {
+                failed = true;
+                break;
+            }
+            entry.retries += 1;
+            entry.deadline = now + self.rtt.backoff(entry.retries);
+            entry.nacked = false;
+            packets.push(entry.packet.clone());
+        }
+        if failed {
+            self.queue.clear();
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "max retransmission attempts exceeded",
+            ));
+        }
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DtcpType;
+    use bytes::BytesMut;
+
+    fn packet(seq_num: u64) -> DtcpPacket<BytesMut> {
+        let mut packet = DtcpPacket::from("ping");
+        packet.set_ty(DtcpType::Transfer { drf: false });
+        packet.set_seq_num(seq_num);
+        packet
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let timeout = Duration::from_millis(10);
+        let mut retx = RetransmissionQueue::new(timeout, timeout, 1);
+        retx.register(packet(0));
+        retx.register(packet(1));
+        retx.register(packet(2));
+        let ack = AckInfo {
+            lwe: 1,
+            high: 3,
+            nacks: vec![(1, 2)],
+        };
+        let now = Instant::now();
+        let acked = retx.ack(&ack, now);
+        assert_eq!(acked.acked, 2);
+        assert!(acked.rtt.is_some());
+        assert_eq!(acked.nacked.len(), 1);
+        assert_eq!(acked.nacked[0].seq_num(), 1);
+        assert!(retx.ack(&ack, now).nacked.is_empty());
+        assert_eq!(retx.len(), 1);
+
+        assert!(retx.expired(now).unwrap().is_empty());
+
+        let packets = retx.expired(now + timeout).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].seq_num(), 1);
+
+        assert!(retx.expired(now + 2 * timeout).is_err());
+        assert!(retx.is_empty());
+    }
+}
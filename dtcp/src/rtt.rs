@@ -0,0 +1,123 @@
+//! Round trip time estimation.
+use std::time::Duration;
+
+/// Clock granularity used as a lower bound of the rtt variance term.
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Estimates the round trip time from ack samples (RFC 6298).
+///
+/// Samples of retransmitted packets are ambiguous and must not be passed to
+/// the estimator (Karn's algorithm).
+///
+/// The retransmission timeout should be set to 2MPL + A + e, where the
+/// estimator provides the estimate of 2MPL + e.
+pub(crate) struct RttEstimator {
+    /// Smoothed round trip time.
+    srtt: Option<Duration>,
+    /// Round trip time variation.
+    rttvar: Duration,
+    /// Maximum time the receiver waits before sending an ack.
+    ack: Duration,
+    /// Upper bound of the retransmission timeout. Used until the first
+    /// sample was taken.
+    max_rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new(ack: Duration, max_rto: Duration) -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            ack,
+            max_rto,
+        }
+    }
+
+    /// Updates the estimate with a new sample.
+    pub fn update(&mut self, rtt: Duration) {
+        match self.srtt {
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+        }
+    }
+
+    /// Returns the smoothed round trip time.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Returns the A-timer interval, A - RTT/2. An ack delayed by the
+    /// receiver should arrive before the sender's retransmission timer
+    /// expires.
+    pub fn a_timer(&self) -> Duration {
+        let rtt = self.srtt.unwrap_or_default();
+        self.ack.checked_sub(rtt / 2).unwrap_or_default()
+    }
+
+    /// Returns the retransmission timeout.
+    pub fn rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => {
+                let rto = srtt + (self.rttvar * 4).max(GRANULARITY) + self.ack;
+                rto.min(self.max_rto)
+            }
+            None => self.max_rto,
+        }
+    }
+
+    /// Returns the retransmission timeout after `retries` retransmissions.
+    /// The timeout doubles with every retransmission.
+    pub fn backoff(&self, retries: u8) -> Duration {
+        let factor = 1u32.checked_shl(retries as u32).unwrap_or(core::u32::MAX);
+        self.rto()
+            .checked_mul(factor)
+            .unwrap_or(self.max_rto)
+            .min(self.max_rto)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt() {
+        let ack = Duration::from_millis(10);
+        let mut rtt = RttEstimator::new(ack, Duration::from_millis(1000));
+        assert_eq!(rtt.rtt(), None);
+        assert_eq!(rtt.rto(), Duration::from_millis(1000));
+        assert_eq!(rtt.a_timer(), ack);
+        rtt.update(Duration::from_millis(100));
+        assert_eq!(rtt.rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.rto(), Duration::from_millis(310));
+        assert_eq!(rtt.a_timer(), Duration::from_millis(0));
+        rtt.update(Duration::from_millis(100));
+        assert_eq!(rtt.rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.rto(), Duration::from_millis(260));
+        rtt.update(Duration::from_millis(900));
+        assert_eq!(rtt.rtt(), Some(Duration::from_millis(200)));
+        assert_eq!(rtt.rto(), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let ack = Duration::from_millis(10);
+        let mut rtt = RttEstimator::new(ack, Duration::from_millis(1000));
+        for _ in 0..20 {
+            rtt.update(Duration::from_millis(100));
+        }
+        let rto = rtt.rto();
+        assert!(rto < Duration::from_millis(200));
+        assert_eq!(rtt.backoff(0), rto);
+        assert_eq!(rtt.backoff(1), rto * 2);
+        assert_eq!(rtt.backoff(10), Duration::from_millis(1000));
+        assert_eq!(rtt.backoff(255), Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,89 @@
+//! Timers used by DTCP.
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_timer::Delay;
+use std::time::{Duration, Instant};
+
+pub(crate) struct Timer {
+    enable: bool,
+    start: Instant,
+    interval: Duration,
+}
+
+impl Timer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            enable: false,
+            start: Instant::now(),
+            interval,
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.start = Instant::now();
+        self.enable = true;
+    }
+
+    pub fn expired(&self) -> bool {
+        self.enable && Instant::now() - self.start > self.interval
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.enable {
+            Some(self.start + self.interval)
+        } else {
+            None
+        }
+    }
+
+    pub fn stop(&mut self) -> bool {
+        if self.enable {
+            self.enable = false;
+            Instant::now() - self.start > self.interval
+        } else {
+            false
+        }
+    }
+}
+
+/// Future returned by `timeout`.
+pub(crate) struct Timeout<F> {
+    future: F,
+    delay: Option<Delay>,
+}
+
+impl<F: Future + Unpin> Future for Timeout<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = Pin::new(&mut self.future).poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        if let Some(delay) = self.delay.as_mut() {
+            if let Poll::Ready(()) = Pin::new(delay).poll(cx) {
+                return Poll::Ready(None);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Resolves when the deadline is reached.
+pub(crate) fn delay_until(deadline: Instant) -> Delay {
+    let now = Instant::now();
+    let interval = if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_millis(0)
+    };
+    Delay::new(interval)
+}
+
+/// Resolves to `None` if the future didn't complete before the deadline.
+///
+/// A deadline of `None` never expires.
+pub(crate) fn timeout<F: Future + Unpin>(deadline: Option<Instant>, future: F) -> Timeout<F> {
+    let delay = deadline.map(delay_until);
+    Timeout { future, delay }
+}
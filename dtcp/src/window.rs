@@ -0,0 +1,96 @@
+//! Window based flow control.
+
+/// Credit based sliding window flow control.
+pub(crate) struct WindowFlowControl {
+    /// Size of the receive window.
+    window: u16,
+    /// Largest sequence number the receiver accepts.
+    send_rwe: u64,
+    /// Next sequence number to deliver to the application.
+    delivered: u64,
+    /// Right window edge advertised to the sender.
+    recv_rwe: u64,
+}
+
+impl WindowFlowControl {
+    pub fn new(window: u16) -> Self {
+        Self {
+            window,
+            send_rwe: window as u64,
+            delivered: 0,
+            recv_rwe: window as u64,
+        }
+    }
+
+    /// Returns `true` if the packet is in the send window.
+    pub fn window_open(&self, seq_num: u64) -> bool {
+        seq_num < self.send_rwe
+    }
+
+    /// Updates the send window with the right window edge advertised by the
+    /// receiver.
+    pub fn update(&mut self, rwe: u64) {
+        self.send_rwe = self.send_rwe.max(rwe);
+    }
+
+    /// Returns `true` if the packet is in the receive window.
+    pub fn in_recv_window(&self, seq_num: u64) -> bool {
+        seq_num < self.recv_rwe
+    }
+
+    /// Registers a packet delivered to the application. Returns `true` if
+    /// enough credit accumulated to send a window update.
+    pub fn deliver(&mut self, seq_num: u64) -> bool {
+        self.delivered = seq_num.saturating_add(1);
+        let credit = self.rwe().saturating_sub(self.recv_rwe);
+        credit >= (self.window as u64 / 2).max(1)
+    }
+
+    /// Right window edge based on the delivered packets.
+    fn rwe(&self) -> u64 {
+        self.delivered.saturating_add(self.window as u64)
+    }
+
+    /// Moves the receive window to the first packet of a new connection.
+    pub fn reset(&mut self, seq_num: u64) {
+        self.delivered = seq_num;
+        self.recv_rwe = self.rwe();
+    }
+
+    /// Returns the right window edge to advertise to the sender.
+    pub fn advertise(&mut self) -> u64 {
+        self.recv_rwe = self.recv_rwe.max(self.rwe());
+        self.recv_rwe
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_window() {
+        let mut window = WindowFlowControl::new(2);
+        assert!(window.window_open(1));
+        assert!(!window.window_open(2));
+        window.update(3);
+        assert!(window.window_open(2));
+        window.update(1);
+        assert!(window.window_open(2));
+    }
+
+    #[test]
+    fn test_recv_window() {
+        let mut window = WindowFlowControl::new(2);
+        assert!(window.in_recv_window(1));
+        assert!(!window.in_recv_window(2));
+        assert!(window.deliver(0));
+        assert_eq!(window.advertise(), 3);
+        assert!(window.in_recv_window(2));
+        assert!(!window.deliver(0));
+
+        window.reset(10);
+        assert!(window.in_recv_window(11));
+        assert!(!window.in_recv_window(12));
+    }
+}
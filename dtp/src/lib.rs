@@ -7,7 +7,9 @@
 //! ## TTL
 //!
 //! ## ECN
-//!
+//! Packets with the `ecn` bit set are sent with the ECT(0) codepoint. The
+//! `ecn` bit of received packets is set if the congestion experienced
+//! codepoint was seen.
 #![deny(missing_docs)]
 #![deny(warnings)]
 mod dtp;
@@ -40,10 +40,22 @@ impl BasePacket for DtpPacket {
         &mut self.bytes[1..]
     }
 
+    fn truncate(&mut self, payload_len: usize) {
+        self.bytes.truncate(payload_len + 1)
+    }
+
     fn debug(&self, ds: &mut std::fmt::DebugStruct) {
         ds.field("ecn", &self.ecn());
         ds.field("channel", &self.channel());
     }
+
+    fn ecn(&self) -> bool {
+        DtpPacket::ecn(self)
+    }
+
+    fn set_ecn(&mut self, ecn: bool) {
+        DtpPacket::set_ecn(self, ecn)
+    }
 }
 
 impl DtpPacket {
@@ -0,0 +1,28 @@
+use addr::Addr;
+use disco::ed25519::PublicKey;
+
+/// Decides which peers are allowed to connect to a socket.
+///
+/// It is invoked after the handshake and protocol negotiation completed,
+/// before an incoming channel is returned. Rejected channels are refused,
+/// the peer's `recv` returns an error of kind `ConnectionRefused`.
+pub trait AccessControl: Send + Sync {
+    /// Returns `true` if the peer is allowed to use the protocol.
+    fn authorize(&self, peer: &PublicKey, protocol: &str, addr: &Addr) -> bool;
+}
+
+impl<F> AccessControl for F
+where
+    F: Fn(&PublicKey, &str, &Addr) -> bool + Send + Sync,
+{
+    fn authorize(&self, peer: &PublicKey, protocol: &str, addr: &Addr) -> bool {
+        self(peer, protocol, addr)
+    }
+}
+
+/// Allows the listed peers.
+impl AccessControl for Vec<PublicKey> {
+    fn authorize(&self, peer: &PublicKey, _protocol: &str, _addr: &Addr) -> bool {
+        self.contains(peer)
+    }
+}
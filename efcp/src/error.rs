@@ -1,17 +1,35 @@
 use failure::Fail;
 
+/// Error establishing an EFCP channel.
 #[derive(Debug, Fail)]
 pub enum HandshakeError {
+    /// Io error of the underlying channel.
     #[fail(display = "{}", _0)]
     Io(std::io::Error),
+    /// Noise handshake error.
     #[fail(display = "{}", _0)]
     Disco(disco::ReadError),
+    /// The peer violated the handshake protocol.
     #[fail(display = "protocol error")]
     ProtocolError,
+    /// No application protocol in common.
     #[fail(display = "protocol negotiation failed")]
     Negotiation,
+    /// The responder didn't send the observed address.
     #[fail(display = "no external addr received")]
     ExternalAddr,
+    /// The dtcp parameters are missing.
+    #[fail(display = "no dtcp parameters received")]
+    DtcpParams,
+    /// The handshake pattern requires the remote public key.
+    #[fail(display = "handshake pattern requires the remote public key")]
+    RemotePublic,
+    /// The remote public key doesn't match the dialed public key.
+    #[fail(display = "unexpected remote identity")]
+    Identity,
+    /// The handshake didn't complete within the handshake timeout.
+    #[fail(display = "handshake timed out")]
+    Timeout,
 }
 
 impl From<std::io::Error> for HandshakeError {
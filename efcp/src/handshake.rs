@@ -0,0 +1,222 @@
+//! Transport of handshake messages.
+use crate::error::HandshakeError;
+use bytes::BufMut;
+use channel::{BasePacket, Channel};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_timer::Delay;
+use std::time::{Duration, Instant};
+
+/// Interval after which the last message is retransmitted the first time.
+/// It is doubled after every retransmission.
+const RETRY: Duration = Duration::from_millis(250);
+
+/// Exchanges handshake messages over an unreliable channel.
+///
+/// While waiting for the next message of the peer the last message is
+/// retransmitted with exponential backoff. A retransmission of the peer
+/// means that our last message was lost, it is answered by retransmitting
+/// our last message. Packets that are not a valid handshake message, like
+/// spoofed packets or data packets sent after the peer finished the
+/// handshake, are dropped.
+pub(crate) struct HandshakeChannel<'a, C> {
+    channel: &'a C,
+    deadline: Instant,
+    sent: Option<Vec<u8>>,
+    received: Option<Vec<u8>>,
+}
+
+impl<'a, C: Channel> HandshakeChannel<'a, C> {
+    pub fn new(channel: &'a C, timeout: Duration) -> Self {
+        Self {
+            channel,
+            deadline: Instant::now() + timeout,
+            sent: None,
+            received: None,
+        }
+    }
+
+    /// Returns when the handshake times out.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Sends a handshake message.
+    pub async fn send(&mut self, msg: Vec<u8>) -> Result<(), HandshakeError> {
+        send_bytes(self.channel, &msg).await?;
+        self.sent = Some(msg);
+        Ok(())
+    }
+
+    /// Records a message that was received before the handshake channel
+    /// was created, so that it's retransmissions are answered.
+    pub fn set_received(&mut self, msg: Vec<u8>) {
+        self.received = Some(msg);
+    }
+
+    /// Receives the next handshake message that `decode` accepts. The
+    /// message is accepted if `decode` succeeds, so it must not change any
+    /// state when it fails.
+    pub async fn recv<T, F>(&mut self, mut decode: F) -> Result<T, HandshakeError>
+    where
+        F: FnMut(&[u8]) -> Result<T, HandshakeError>,
+    {
+        let mut retry = RETRY;
+        let mut next_retry = Instant::now() + retry;
+        loop {
+            if Instant::now() >= self.deadline {
+                return Err(HandshakeError::Timeout);
+            }
+            let deadline = next_retry.min(self.deadline);
+            let packet = match timeout(deadline, self.channel.recv()).await {
+                Some(packet) => packet?,
+                None => {
+                    self.retransmit().await?;
+                    retry *= 2;
+                    next_retry = Instant::now() + retry;
+                    continue;
+                }
+            };
+            if self.received.as_ref().map(|msg| &msg[..]) == Some(packet.payload()) {
+                self.retransmit().await?;
+                continue;
+            }
+            let msg = match decode(packet.payload()) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            self.received = Some(packet.payload().to_vec());
+            return Ok(msg);
+        }
+    }
+
+    async fn retransmit(&self) -> Result<(), HandshakeError> {
+        if let Some(msg) = self.sent.as_ref() {
+            send_bytes(self.channel, msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the last received and the last sent message.
+    ///
+    /// If the handshake finished with our message and it was lost, the peer
+    /// retransmits the returned received message.
+    pub fn into_last(self) -> Option<(Vec<u8>, Vec<u8>)> {
+        match (self.received, self.sent) {
+            (Some(received), Some(sent)) => Some((received, sent)),
+            _ => None,
+        }
+    }
+}
+
+/// Sends a message as a packet of the channel.
+pub(crate) async fn send_bytes<C: Channel>(channel: &C, msg: &[u8]) -> std::io::Result<()> {
+    let mut packet = C::Packet::new(msg.len());
+    packet.put_slice(msg);
+    channel.send(packet).await
+}
+
+/// Returns `HandshakeError::Timeout` if the future doesn't complete before
+/// the deadline.
+pub(crate) async fn before<F: Future + Unpin>(
+    deadline: Instant,
+    future: F,
+) -> Result<F::Output, HandshakeError> {
+    timeout(deadline, future)
+        .await
+        .ok_or(HandshakeError::Timeout)
+}
+
+/// Future returned by `timeout`.
+struct Timeout<F> {
+    future: F,
+    delay: Delay,
+}
+
+impl<F: Future + Unpin> Future for Timeout<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = Pin::new(&mut self.future).poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        if let Poll::Ready(()) = Pin::new(&mut self.delay).poll(cx) {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+/// Resolves to `None` if the future didn't complete before the deadline.
+fn timeout<F: Future + Unpin>(deadline: Instant, future: F) -> Timeout<F> {
+    let now = Instant::now();
+    let interval = if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_millis(0)
+    };
+    Timeout {
+        future,
+        delay: Delay::new(interval),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use test_channel::LossyChannelBuilder;
+
+    fn decode(msg: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        if msg.starts_with(b"m") {
+            Ok(msg.to_vec())
+        } else {
+            Err(HandshakeError::ProtocolError)
+        }
+    }
+
+    #[test]
+    fn test_handshake_duplicates() {
+        // Every packet is received twice.
+        let (c1, c2) = LossyChannelBuilder::new(1.0, 1.0).split();
+        let mut h1 = HandshakeChannel::new(&c1, Duration::from_secs(5));
+        let mut h2 = HandshakeChannel::new(&c2, Duration::from_secs(5));
+        task::block_on(async {
+            h1.send(b"m1".to_vec()).await.unwrap();
+            assert_eq!(h2.recv(decode).await.unwrap(), b"m1");
+            h2.send(b"m2".to_vec()).await.unwrap();
+            assert_eq!(h1.recv(decode).await.unwrap(), b"m2");
+            h1.send(b"m3".to_vec()).await.unwrap();
+            assert_eq!(h2.recv(decode).await.unwrap(), b"m3");
+        });
+        assert_eq!(h2.into_last(), Some((b"m3".to_vec(), b"m2".to_vec())));
+    }
+
+    #[test]
+    fn test_handshake_invalid() {
+        let (c1, c2) = LossyChannelBuilder::new(1.0, 0.0).split();
+        let mut h1 = HandshakeChannel::new(&c1, Duration::from_secs(5));
+        let mut h2 = HandshakeChannel::new(&c2, Duration::from_secs(5));
+        task::block_on(async {
+            send_bytes(&c1, b"spoofed").await.unwrap();
+            h1.send(b"m1".to_vec()).await.unwrap();
+            assert_eq!(h2.recv(decode).await.unwrap(), b"m1");
+        });
+        assert_eq!(h2.into_last(), None);
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        // Network partition.
+        let (c1, _c2) = LossyChannelBuilder::new(0.0, 0.0).split();
+        let mut h1 = HandshakeChannel::new(&c1, Duration::from_millis(600));
+        task::block_on(async {
+            h1.send(b"m1".to_vec()).await.unwrap();
+            match h1.recv(decode).await {
+                Err(HandshakeError::Timeout) => {}
+                _ => panic!("expected a timeout"),
+            }
+        });
+    }
+}
@@ -7,18 +7,35 @@
 //!
 //! ## Security considerations
 //! Padding: All messages must be padded to equal length to prevent information
-//!   leakage.
+//!   leakage. The padding policy is configured with `EfcpSocket::set_padding`,
+//!   the padding length is part of the ciphertext.
 //! Termination: Session termination must be made explicit to prevent truncation
-//!   attacks.
+//!   attacks. The close frame is a DTCP packet with the `fin` flag, which is
+//!   encrypted and authenticated like any other packet. `shutdown_write` returns
+//!   once the peer acknowledged it and the peer's `recv` returns a packet with
+//!   the `fin` flag. A stream that stops without a close frame is reported as
+//!   an error of kind `UnexpectedEof`.
 //! Nonces: Every message has a clear text nonce. Nonces are not allowed to wrap
 //!   since that would open the possibility of messages being replayed. For this
 //!   reason the nonce must be  minimum 64 bits in length. The assumption is
 //!   made that with todays technology a nonce of 64-bits will never wrap.
+//!   Received nonces are tracked in a sliding window, replayed and too old
+//!   messages are dropped before decryption.
+//! Rekeying: Long lived sessions are rekeyed after a number of bytes, messages
+//!   or an elapsed time, configured with `EfcpSocket::set_rekey_policy`. The
+//!   rekey is an `NN` handshake sent over the encrypted channel. Old keys are
+//!   kept for a grace period to decrypt packets in flight.
 //!
 //! ## Handshake
 //! The default handshake is based on the `XK1sig` pattern from the noise
 //! signature extension spec. The client identity is useful for performing
-//! access control operations.
+//! access control operations, see `EfcpSocket::set_access_control`. The `IK`
+//! and `XX` patterns can be configured with `EfcpSocket::set_pattern`. `IK`
+//! completes in a single round trip, `XX` doesn't require the dialer to know
+//! the peer's public key.
+//! Lost handshake messages are retransmitted with exponential backoff. A
+//! handshake that doesn't complete within the handshake timeout fails with
+//! `HandshakeError::Timeout`.
 //!
 //! ```no_build
 //! XK1sig
@@ -36,41 +53,78 @@
 //! address and port.
 //!
 //! ## Generic protocol negotiation
-//! Based on the libp2p connection spec, a protocol identifier is sent by the
-//! initiator to request a protocol. The responder can accept by echoing the
-//! protocol identifier or deny with a N/A message. On failure the negotiation
-//! can be restarted. This process is (by default) started in parallel with the
-//! handshake to reduce latency.
+//! Based on the libp2p connection spec, the initiator sends the protocol
+//! identifiers it supports in order of preference. The responder accepts the
+//! first one it supports by echoing the protocol identifier or denies with a
+//! N/A message. The proposal is sent in the first handshake message and the
+//! answer in the second, so the negotiation completes within the handshake.
+//! When both peers initiate, as on hole punched links, each proposal carries
+//! a random nonce. The peer with the larger nonce continues as the responder
+//! and the negotiation completes like any other.
 //!
 //! ### DTCP parameter negotiation
 //! The congestion and flow control elements of the protocol have different
-//! parameters to make it tunable to a specific application. These are
-//! negotiated using the generic protocol negotiation mechanism.
+//! parameters to make it tunable to a specific application. The initiator
+//! proposes its parameters in the first handshake message and the responder
+//! answers with the agreed parameters in the second. The timers and retries
+//! tolerate the slower peer, the window fits the smaller receive buffer and
+//! the initiator's congestion control algorithm wins. The responder bounds
+//! the agreed parameters, see `EfcpSocket::set_dtcp_limits`.
 //!
 //! ### Application protocol negotiation
 //! The application protocol has a unique protocol identifier, which is used
 //! to negotiate the application protocol using the generic protocol negotiation
-//! mechanism.
+//! mechanism. A socket supports the protocols it was bound with and the
+//! protocols registered with `EfcpSocket::listen`. Channels proposing only
+//! other protocols are refused during the negotiation. Each listener yields
+//! the channels that negotiated its protocol, `EfcpSocket::incoming` yields
+//! the channels of protocols without a listener.
 #![deny(missing_docs)]
 #![deny(warnings)]
+mod access;
 mod error;
+mod handshake;
 mod negotiation;
 mod packet;
+mod params;
+mod pattern;
+mod rekey;
+mod replay;
+mod router;
 mod secure;
 
-use crate::error::HandshakeError;
-use crate::negotiation::{Negotiation, Protocol, Protocols};
+pub use crate::access::AccessControl;
+pub use crate::error::HandshakeError;
+use crate::handshake::{before, HandshakeChannel};
+use crate::negotiation::{Message, Negotiation};
+pub use crate::negotiation::{Protocol, Protocols};
 use crate::packet::HandshakePacket;
+pub use crate::params::{DtcpLimits, DtcpParams};
+pub use crate::pattern::Pattern;
+pub use crate::rekey::RekeyPolicy;
+use crate::router::Router;
+pub use crate::secure::Padding;
 use crate::secure::{DiscoChannel, DiscoPacket};
 use addr::{Addr, ToAddr};
-use async_std::prelude::*;
+use async_std::stream::Stream;
 use async_trait::async_trait;
 use channel::{BasePacket, Channel};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 pub use disco::ed25519::{Keypair, PublicKey};
 use disco::SessionBuilder;
-use dtcp::{DtcpBuilder, DtcpChannel, DtcpPacket};
-use dtp::{DtpChannel, DtpPacket, DtpSocket};
+pub use dtcp::CongestionControl;
+use dtcp::{DtcpChannel, DtcpPacket, Notify};
+use dtp::{DtpChannel, DtpSocket};
 use std::io::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Maximum number of concurrent incoming handshakes. Further channels wait
+/// in the queue of the dtp socket.
+const MAX_HANDSHAKES: usize = 64;
 
 /// The information required to dial a peer.
 pub struct Dial {
@@ -78,9 +132,10 @@ pub struct Dial {
     pub peer_addr: Addr,
     /// DTP channel id.
     pub channel: u8,
-    /// Peer's public key.
-    pub remote_public: PublicKey,
-    /// Acceptable application protocols.
+    /// Peer's public key. Required unless the handshake pattern is `XX`,
+    /// in which case it is verified if present.
+    pub remote_public: Option<PublicKey>,
+    /// Application protocols to propose in order of preference.
     pub protocols: Protocols,
 }
 
@@ -119,7 +174,7 @@ pub struct Dial {
 /// # let remote_public = Keypair::generate(&mut OsRng).public;
 /// #
 /// use channel::{BasePacket, Channel};
-/// use efcp::{Dial, EfcpSocket, Keypair};
+/// use efcp::{Dial, EfcpSocket, Keypair, Protocols};
 /// use rand::rngs::OsRng;
 ///
 /// let identity = Keypair::generate(&mut OsRng);
@@ -131,8 +186,8 @@ pub struct Dial {
 /// let dial = Dial {
 ///     peer_addr: "/ip4/127.0.0.1/udp/8000".parse()?,
 ///     channel: 0,
-///     remote_public,
-///     protocols: &["/ping/1.0"],
+///     remote_public: Some(remote_public),
+///     protocols: Protocols::new(&["/ping/1.0"]),
 /// };
 /// let channel = socket.dial(&dial).await?;
 /// channel.send("ping".into());
@@ -142,44 +197,256 @@ pub struct Dial {
 /// ```
 pub struct EfcpSocket {
     dtp: DtpSocket,
-    identity: Keypair,
+    identity: Arc<Keypair>,
     protocols: Protocols,
+    options: Options,
+    access: Option<Box<dyn AccessControl>>,
+    router: Mutex<Router<Result<EfcpChannel, HandshakeError>>>,
+    notify: Notify,
+    /// Incoming handshakes in progress.
+    handshakes: Mutex<Vec<Handshake>>,
+    failed_handshakes: AtomicU64,
+}
+
+/// Responder handshake of an incoming channel.
+type Handshake = Pin<Box<dyn Future<Output = Result<EfcpChannel, HandshakeError>> + Send>>;
+
+/// Options of the channels of a socket.
+#[derive(Clone, Copy, Debug)]
+struct Options {
+    padding: Padding,
+    rekey: RekeyPolicy,
+    pattern: Pattern,
+    handshake_timeout: Duration,
+    dtcp: DtcpParams,
+    dtcp_limits: DtcpLimits,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            padding: Padding::default(),
+            rekey: RekeyPolicy::default(),
+            pattern: Pattern::default(),
+            handshake_timeout: Duration::from_secs(10),
+            dtcp: DtcpParams::default(),
+            dtcp_limits: DtcpLimits::default(),
+        }
+    }
 }
 
 impl EfcpSocket {
-    /// Creates a new `EfcpSocket`.
-    pub async fn bind<T: ToAddr>(
-        addr: T,
-        identity: Keypair,
-        protocols: Protocols,
-    ) -> Result<Self, Error> {
+    /// Creates a new `EfcpSocket` supporting the protocols in order of
+    /// preference.
+    pub async fn bind<T, I, S>(addr: T, identity: Keypair, protocols: I) -> Result<Self, Error>
+    where
+        T: ToAddr,
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let protocols = Protocols::new(protocols);
         let dtp = DtpSocket::bind(addr).await?;
         Ok(Self {
             dtp,
-            identity,
+            identity: Arc::new(identity),
             protocols,
+            options: Options::default(),
+            access: None,
+            router: Mutex::new(Router::new()),
+            notify: Notify::new(),
+            handshakes: Mutex::new(Vec::new()),
+            failed_handshakes: AtomicU64::new(0),
         })
     }
 
+    /// Sets the padding policy of the channels of this socket. Defaults to
+    /// `Padding::Off`.
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.options.padding = padding;
+    }
+
+    /// Sets the rekeying policy of the channels of this socket.
+    pub fn set_rekey_policy(&mut self, rekey: RekeyPolicy) {
+        self.options.rekey = rekey;
+    }
+
+    /// Sets the handshake pattern of incoming and outgoing channels.
+    /// Defaults to `Pattern::XK1sig`.
+    pub fn set_pattern(&mut self, pattern: Pattern) {
+        self.options.pattern = pattern;
+    }
+
+    /// Sets the time after which a handshake fails with
+    /// `HandshakeError::Timeout`. Defaults to 10s.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.options.handshake_timeout = timeout;
+    }
+
+    /// Sets the DTCP parameters proposed to and accepted from peers.
+    pub fn set_dtcp_params(&mut self, params: DtcpParams) {
+        self.options.dtcp = params;
+    }
+
+    /// Sets the limits of the DTCP parameters agreed with peers.
+    pub fn set_dtcp_limits(&mut self, limits: DtcpLimits) {
+        self.options.dtcp_limits = limits;
+    }
+
+    /// Sets the access control of incoming channels. By default all peers
+    /// that complete the handshake are accepted.
+    ///
+    /// ```no_run
+    /// # use efcp::{EfcpSocket, Keypair};
+    /// # use rand::rngs::OsRng;
+    /// # fn main() -> Result<(), failure::Error> { async_std::task::block_on(async {
+    /// # let identity = Keypair::generate(&mut OsRng);
+    /// # let peer = Keypair::generate(&mut OsRng).public;
+    /// let mut socket = EfcpSocket::bind("/ip4/0.0.0.0", identity, &["/ping/1.0"]).await?;
+    /// socket.set_access_control(vec![peer]);
+    /// # Ok(()) }) }
+    /// ```
+    pub fn set_access_control<A: AccessControl + 'static>(&mut self, access: A) {
+        self.access = Some(Box::new(access));
+    }
+
     /// Returns a stream of incoming EFCP connections.
+    ///
+    /// Channels that negotiated a protocol with a listener are yielded by
+    /// the listener instead. Channels rejected by the access control are
+    /// refused and skipped. Failed handshakes are returned as errors. The
+    /// handshakes of incoming channels run concurrently while a task waits
+    /// for a channel.
     pub async fn incoming(&self) -> Option<Result<EfcpChannel, HandshakeError>> {
-        match self.dtp.incoming().next().await {
-            Some(Ok(channel)) => {
-                let peer_addr = channel.peer_addr().clone();
-                let efcp =
-                    EfcpChannel::responder(channel, &self.identity, self.protocols, peer_addr)
-                        .await;
-                Some(efcp)
+        self.route(None).await
+    }
+
+    /// Returns a listener for channels that negotiated the protocol. The
+    /// protocol is supported in addition to the protocols of the socket
+    /// until the listener is dropped.
+    ///
+    /// ```no_run
+    /// # use efcp::{EfcpSocket, Keypair};
+    /// # use rand::rngs::OsRng;
+    /// # fn main() -> Result<(), failure::Error> { async_std::task::block_on(async {
+    /// # let identity = Keypair::generate(&mut OsRng);
+    /// let socket = EfcpSocket::bind("/ip4/0.0.0.0", identity, Vec::<&str>::new()).await?;
+    /// let ping = socket.listen("/ping/1.0");
+    /// while let Some(channel) = ping.incoming().await {
+    ///     assert_eq!(channel?.protocol(), "/ping/1.0");
+    /// }
+    /// # Ok(()) }) }
+    /// ```
+    pub fn listen<S: AsRef<str>>(&self, protocol: S) -> Listener<'_> {
+        let protocol: Protocol = protocol.as_ref().into();
+        self.router.lock().unwrap().listen(protocol.clone());
+        Listener {
+            socket: self,
+            protocol,
+        }
+    }
+
+    /// Returns the next channel of the protocol. A single task accepts
+    /// channels at a time and queues the channels of other protocols and
+    /// the failed handshakes.
+    async fn route(&self, protocol: Option<&str>) -> Option<Result<EfcpChannel, HandshakeError>> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut router = self.router.lock().unwrap();
+                if let Some(channel) = router.pop(protocol) {
+                    return Some(channel);
+                }
+                if router.is_closed() {
+                    return None;
+                }
+            }
+            let _guard = match self.notify.try_lock() {
+                Some(guard) => guard,
+                None => {
+                    notified.await;
+                    continue;
+                }
+            };
+            // Dropping the guard wakes the other tasks.
+            match (Accept { socket: self }).await {
+                Some(Ok(Ok(channel))) => {
+                    if !self.authorize(&channel) {
+                        // Doesn't wait for the peer, so an unresponsive peer
+                        // can't delay other channels.
+                        channel.refuse().await.ok();
+                        continue;
+                    }
+                    let protocol = channel.protocol.clone();
+                    let full = self.router.lock().unwrap().push(&protocol, Ok(channel));
+                    if let Err(Ok(channel)) = full {
+                        channel.refuse().await.ok();
+                    }
+                }
+                Some(Ok(Err(err))) => {
+                    self.failed_handshakes.fetch_add(1, Ordering::SeqCst);
+                    // Listeners skip the failed handshakes.
+                    self.router.lock().unwrap().push_default(Err(err)).ok();
+                }
+                Some(Err(err)) => return Some(Err(err.into())),
+                None => self.router.lock().unwrap().close(),
+            }
+        }
+    }
+
+    /// Returns `true` if the access control accepts the channel.
+    fn authorize(&self, channel: &EfcpChannel) -> bool {
+        match self.access.as_ref() {
+            Some(access) => access.authorize(
+                &channel.peer_identity(),
+                channel.protocol(),
+                channel.peer_addr(),
+            ),
+            None => true,
+        }
+    }
+
+    /// Starts the responder handshake of an incoming channel.
+    fn handshake(&self, channel: DtpChannel) -> Handshake {
+        let identity = self.identity.clone();
+        let protocols = self.supported_protocols();
+        let options = self.options;
+        let peer_addr = channel.peer_addr().clone();
+        Box::pin(async move {
+            EfcpChannel::responder(channel, &identity, protocols, options, peer_addr, None).await
+        })
+    }
+
+    /// Returns the protocols of the socket followed by the protocols of the
+    /// listeners.
+    fn supported_protocols(&self) -> Protocols {
+        let router = self.router.lock().unwrap();
+        let mut protocols = self.protocols.to_vec();
+        for protocol in router.protocols() {
+            if !protocols.contains(protocol) {
+                protocols.push(protocol.clone());
             }
-            Some(Err(err)) => Some(Err(err.into())),
-            None => None,
         }
+        protocols.into()
     }
 
     /// Dials a peer.
+    ///
+    /// If the peer dials at the same time, for example to open a hole
+    /// punched link, one of the peers takes the responder role. The roles
+    /// are selected by the nonces of the protocol proposals, or by the
+    /// public keys if the nonces are equal.
     pub async fn dial(&self, dial: &Dial) -> Result<EfcpChannel, HandshakeError> {
         let channel = self.dtp.outgoing(dial.peer_addr, dial.channel)?;
-        EfcpChannel::initiator(channel, &self.identity, self.protocols, dial.remote_public).await
+        let peer_addr = channel.peer_addr().clone();
+        EfcpChannel::initiator(
+            channel,
+            &self.identity,
+            dial.protocols.clone(),
+            self.options,
+            dial.remote_public,
+            Some(peer_addr),
+        )
+        .await
     }
 
     /// Returns the local address that this socket is bound to.
@@ -192,88 +459,257 @@ impl EfcpSocket {
         self.identity.public
     }
 
+    /// Returns the number of incoming handshakes that failed, for example
+    /// because the peer proposed no supported protocol or timed out.
+    pub fn failed_handshakes(&self) -> u64 {
+        self.failed_handshakes.load(Ordering::SeqCst)
+    }
+
     /// Returns the list of protocol supported on this socket.
-    pub fn protocols(&self) -> Protocols {
-        self.protocols
+    pub fn protocols(&self) -> &Protocols {
+        &self.protocols
+    }
+}
+
+/// Future returned when an incoming handshake finished or the dtp socket
+/// failed. Resolves to `None` once the socket is closed and no handshake is
+/// in progress.
+struct Accept<'a> {
+    socket: &'a EfcpSocket,
+}
+
+impl<'a> Future for Accept<'a> {
+    type Output = Option<Result<Result<EfcpChannel, HandshakeError>, Error>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let socket = self.socket;
+        let mut handshakes = socket.handshakes.lock().unwrap();
+        let mut closed = false;
+        while handshakes.len() < MAX_HANDSHAKES {
+            match Pin::new(&mut socket.dtp.incoming()).poll_next(cx) {
+                Poll::Ready(Some(Ok(channel))) => handshakes.push(socket.handshake(channel)),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    closed = true;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+        for i in 0..handshakes.len() {
+            if let Poll::Ready(res) = handshakes[i].as_mut().poll(cx) {
+                handshakes.swap_remove(i);
+                return Poll::Ready(Some(Ok(res)));
+            }
+        }
+        if closed && handshakes.is_empty() {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+/// Yields the incoming channels of a protocol, created with
+/// `EfcpSocket::listen`.
+pub struct Listener<'a> {
+    socket: &'a EfcpSocket,
+    protocol: Protocol,
+}
+
+impl<'a> Listener<'a> {
+    /// Returns a stream of incoming EFCP connections that negotiated the
+    /// protocol of this listener. Failed handshakes are returned by
+    /// `EfcpSocket::incoming`.
+    pub async fn incoming(&self) -> Option<Result<EfcpChannel, HandshakeError>> {
+        self.socket.route(Some(&*self.protocol)).await
+    }
+
+    /// Returns the protocol of this listener.
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+}
+
+impl<'a> Drop for Listener<'a> {
+    fn drop(&mut self) {
+        self.socket.router.lock().unwrap().unlisten(&self.protocol);
+    }
+}
+
+/// Handshake message received by the initiator.
+enum Received {
+    /// Message of the responder.
+    Response(Vec<u8>),
+    /// First message of a peer that initiated at the same time, to which we
+    /// respond.
+    Initiated(Addr, Vec<u8>),
+}
+
+/// Returns the nonce of the protocol proposal in the first message of an
+/// initiator.
+fn proposal_nonce(ct: &[u8], identity: &Keypair, pattern: Pattern) -> Result<u64, HandshakeError> {
+    let mut session = SessionBuilder::new(pattern.name())
+        .secret(identity)
+        .build_responder();
+    let pt = session.read_message(ct)?;
+    match HandshakePacket::from_bytes(&pt)?.negotiate() {
+        Some(Message::Propose { nonce, .. }) => Ok(nonce),
+        _ => Err(HandshakeError::ProtocolError),
     }
 }
 
 /// A EFCP channel between a local and a remote socket.
-pub struct EfcpChannel {
-    channel: DtcpChannel<DiscoChannel<DtpChannel>>,
+pub struct EfcpChannel<C = DtpChannel> {
+    channel: DtcpChannel<DiscoChannel<C>>,
     remote: PublicKey,
     protocol: Protocol,
+    params: DtcpParams,
     external_addr: Option<Addr>,
 }
 
-impl EfcpChannel {
+impl<C: Channel> EfcpChannel<C> {
+    /// Performs the handshake of the initiator. If `remote_addr` is set and
+    /// the peer initiated at the same time, continues as the responder
+    /// unless the peer does.
     async fn initiator(
-        channel: DtpChannel,
+        channel: C,
         identity: &Keypair,
         protocols: Protocols,
-        remote_public: PublicKey,
+        options: Options,
+        remote_public: Option<PublicKey>,
+        remote_addr: Option<Addr>,
     ) -> Result<Self, HandshakeError> {
-        let dtcp = DtcpBuilder::new();
-        let channel = dtcp.build_channel(channel);
-        let mut session = SessionBuilder::new("XK1sig")
-            .secret(identity)
-            .remote_public(remote_public)
-            .build_initiator();
-        let mut negotiate = Negotiation::new(protocols);
+        let mut handshake = HandshakeChannel::new(&channel, options.handshake_timeout);
+        let deadline = handshake.deadline();
+        let mut builder = SessionBuilder::new(options.pattern.name()).secret(identity);
+        if let Some(remote_public) = remote_public {
+            builder = builder.remote_public(remote_public);
+        } else if options.pattern.requires_remote_public() {
+            return Err(HandshakeError::RemotePublic);
+        }
+        let mut session = builder.build_initiator();
+        let mut negotiate = Negotiation::new(protocols.clone());
         let mut external_addr = None;
         let mut next_neg = Some(negotiate.initiate());
+        let mut proposal = Some(options.dtcp);
+        let mut params = None;
+        let mut sent_last = false;
+        let mut first = true;
 
         loop {
-            let msg = HandshakePacket::new(next_neg.take(), None);
+            let msg = HandshakePacket::new(next_neg.take(), proposal.take(), None);
             let ct = session.write_message(&msg.to_bytes()?);
-            channel.send(ct[..].into()).await?;
+            handshake.send(ct).await?;
 
             if session.is_handshake_finished() {
+                sent_last = true;
                 break;
             }
 
-            let packet = channel.recv().await?;
-            let pt = session.read_message(packet.payload())?;
+            // Packets that fail to decrypt or parse are dropped without
+            // advancing the session.
+            let received = handshake
+                .recv(|ct| {
+                    let mut next = session.clone();
+                    if let Ok(pt) = next.read_message(ct) {
+                        if HandshakePacket::from_bytes(&pt).is_ok() {
+                            session = next;
+                            return Ok(Received::Response(pt));
+                        }
+                    }
+                    // First message of a peer that initiated at the same
+                    // time.
+                    if let Some(remote_addr) = remote_addr.filter(|_| first) {
+                        let nonce = proposal_nonce(ct, identity, options.pattern)?;
+                        let local = &identity.public;
+                        if negotiate.responds_to(nonce, local, remote_public.as_ref())? {
+                            return Ok(Received::Initiated(remote_addr, ct.to_vec()));
+                        }
+                    }
+                    Err(HandshakeError::ProtocolError)
+                })
+                .await?;
+            let pt = match received {
+                Received::Response(pt) => pt,
+                Received::Initiated(remote_addr, ct) => {
+                    let efcp = Self::responder(
+                        channel,
+                        identity,
+                        protocols,
+                        options,
+                        remote_addr,
+                        Some(ct),
+                    )
+                    .await?;
+                    if remote_public.map(|key| key != efcp.remote).unwrap_or(false) {
+                        return Err(HandshakeError::Identity);
+                    }
+                    return Ok(efcp);
+                }
+            };
+            first = false;
             let mut msg = HandshakePacket::from_bytes(&pt)?;
             if let Some(addr) = msg.external_addr() {
                 external_addr = Some(addr);
             }
+            if let Some(agreed) = msg.params() {
+                if !options.dtcp.accepts(&agreed, &options.dtcp_limits) {
+                    return Err(HandshakeError::DtcpParams);
+                }
+                params = Some(agreed);
+            }
             next_neg = msg
                 .negotiate()
                 .as_ref()
                 .map(|msg| negotiate.message(msg))
                 .unwrap_or(Ok(None))?;
+
+            if session.is_handshake_finished() {
+                break;
+            }
         }
 
         let remote = *session
             .get_remote_static()
-            .expect("XK1sig handshake; qed")
+            .expect("handshake finished; qed")
             .ed25519();
+        if remote_public.map(|key| key != remote).unwrap_or(false) {
+            return Err(HandshakeError::Identity);
+        }
         let session = session.into_stateless_transport_mode();
 
-        let channel = channel.unwrap();
-        let channel = DiscoChannel::new(channel, session);
-        let channel = dtcp.build_channel(channel);
+        let params = params.ok_or(HandshakeError::DtcpParams)?;
+        let last = handshake.into_last().filter(|_| sent_last);
+        let channel = DiscoChannel::new(channel, session, options.padding, options.rekey);
+        if let Some((received, sent)) = last {
+            channel.set_handshake(received, sent);
+        }
+        let channel = params.builder().build_channel(channel);
 
         if external_addr.is_none() {
             return Err(HandshakeError::ExternalAddr);
         }
 
-        while !negotiate.is_finished() {
-            let packet = channel.recv().await?;
+        // Depending on the pattern the handshake ends with a message of the
+        // initiator or the responder, so either side can have a pending
+        // negotiation message.
+        loop {
+            if let Some(msg) = next_neg.take() {
+                let msg = HandshakePacket::new(Some(msg), None, None).to_bytes()?;
+                channel.send(msg[..].into()).await?;
+            }
+
+            if negotiate.is_finished() {
+                break;
+            }
+
+            let packet = before(deadline, channel.recv()).await??;
             let mut msg = HandshakePacket::from_bytes(packet.payload())?;
             next_neg = msg
                 .negotiate()
                 .as_ref()
                 .map(|msg| negotiate.message(msg))
                 .unwrap_or(Ok(None))?;
-
-            if next_neg.is_none() {
-                break;
-            }
-
-            let msg = HandshakePacket::new(next_neg.take(), None).to_bytes()?;
-            channel.send(msg[..].into()).await?;
         }
         let protocol = negotiate
             .into_protocol()
@@ -284,64 +720,103 @@ impl EfcpChannel {
             channel,
             remote,
             protocol,
+            params,
             external_addr,
         })
     }
 
+    /// Performs the handshake of the responder. `first` is the first
+    /// message of the initiator if it was already received.
     async fn responder(
-        channel: DtpChannel,
+        channel: C,
         identity: &Keypair,
         protocols: Protocols,
+        options: Options,
         remote_addr: Addr,
+        mut first: Option<Vec<u8>>,
     ) -> Result<Self, HandshakeError> {
-        let dtcp = DtcpBuilder::new();
-        let channel = dtcp.build_channel(channel);
-        let mut session = SessionBuilder::new("XK1sig")
+        let mut handshake = HandshakeChannel::new(&channel, options.handshake_timeout);
+        let deadline = handshake.deadline();
+        let mut session = SessionBuilder::new(options.pattern.name())
             .secret(identity)
             .build_responder();
         let mut negotiate = Negotiation::new(protocols);
         let mut external_addr = Some(remote_addr);
         let mut next_neg;
+        let mut params = None;
+        let mut answer = None;
+        let mut sent_last = false;
 
         loop {
-            let dtcp = channel.recv().await?;
-            let bytes = session.read_message(dtcp.payload())?;
-            let mut msg = HandshakePacket::from_bytes(&bytes[..])?;
+            // Packets that fail to decrypt or parse are dropped without
+            // advancing the session.
+            let mut decode = |ct: &[u8]| -> Result<Vec<u8>, HandshakeError> {
+                let mut next = session.clone();
+                let pt = next.read_message(ct)?;
+                HandshakePacket::from_bytes(&pt)?;
+                session = next;
+                Ok(pt)
+            };
+            let pt = match first.take() {
+                Some(ct) => {
+                    let pt = decode(&ct[..])?;
+                    handshake.set_received(ct);
+                    pt
+                }
+                None => handshake.recv(decode).await?,
+            };
+            let mut msg = HandshakePacket::from_bytes(&pt)?;
             next_neg = msg
                 .negotiate()
                 .as_ref()
                 .map(|msg| negotiate.message(msg))
                 .unwrap_or(Ok(None))?;
+            if let Some(proposed) = msg.params() {
+                let agreed = options.dtcp.agree(&proposed, &options.dtcp_limits);
+                params = Some(agreed);
+                answer = Some(agreed);
+            }
 
             if session.is_handshake_finished() {
                 break;
             }
 
-            let msg = HandshakePacket::new(next_neg, external_addr.take());
+            let msg = HandshakePacket::new(next_neg.take(), answer.take(), external_addr.take());
             let ct = session.write_message(&msg.to_bytes()?);
-            let dtcp = DtcpPacket::from(&ct[..]);
-            channel.send(dtcp).await?;
+            handshake.send(ct).await?;
+
+            if session.is_handshake_finished() {
+                sent_last = true;
+                break;
+            }
         }
 
         let remote = *session
             .get_remote_static()
-            .expect("XK1sig handshake; qed")
+            .expect("handshake finished; qed")
             .ed25519();
         let session = session.into_stateless_transport_mode();
 
-        let channel = channel.unwrap();
-        let channel = DiscoChannel::new(channel, session);
-        let channel = dtcp.build_channel(channel);
+        let params = params.ok_or(HandshakeError::DtcpParams)?;
+        let last = handshake.into_last().filter(|_| sent_last);
+        let channel = DiscoChannel::new(channel, session, options.padding, options.rekey);
+        if let Some((received, sent)) = last {
+            channel.set_handshake(received, sent);
+        }
+        let channel = params.builder().build_channel(channel);
 
-        while let Some(msg) = next_neg.take() {
-            let msg = HandshakePacket::new(Some(msg), external_addr.take()).to_bytes()?;
-            channel.send(msg[..].into()).await?;
+        loop {
+            if let Some(msg) = next_neg.take() {
+                let msg = HandshakePacket::new(Some(msg), None, external_addr.take());
+                let msg = msg.to_bytes()?;
+                channel.send(msg[..].into()).await?;
+            }
 
             if negotiate.is_finished() {
                 break;
             }
 
-            let packet = channel.recv().await?;
+            let packet = before(deadline, channel.recv()).await??;
             let mut msg = HandshakePacket::from_bytes(packet.payload())?;
             next_neg = msg
                 .negotiate()
@@ -358,10 +833,56 @@ impl EfcpChannel {
             channel,
             remote,
             protocol,
+            params,
             external_addr,
         })
     }
 
+    /// Returns the external address that the remote observed.
+    pub fn external_addr(&self) -> Option<&Addr> {
+        self.external_addr.as_ref()
+    }
+
+    /// Returns the public key of the peer that this channel is connected to.
+    pub fn peer_identity(&self) -> PublicKey {
+        self.remote
+    }
+
+    /// Returns the protocol that this channel has negotiated.
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    /// Returns the DTCP parameters that this channel has negotiated.
+    pub fn dtcp_params(&self) -> &DtcpParams {
+        &self.params
+    }
+
+    /// Returns the number of replayed packets that were rejected. A growing
+    /// number indicates an active attack.
+    pub fn rejected_replays(&self) -> u64 {
+        self.channel.rejected_replays()
+    }
+
+    /// Shuts down writing after all outstanding data has been acknowledged.
+    /// The peer's `recv` returns a packet with the `fin` flag after the last
+    /// packet.
+    pub async fn shutdown_write(&self) -> Result<(), Error> {
+        self.channel.shutdown_write().await
+    }
+
+    /// Shuts down reading and writing.
+    pub async fn close(&self) -> Result<(), Error> {
+        self.channel.close().await
+    }
+
+    /// Notifies the peer that the channel was refused.
+    async fn refuse(&self) -> Result<(), Error> {
+        self.channel.refuse().await
+    }
+}
+
+impl EfcpChannel<DtpChannel> {
     /// Returns the local address that this bound to.
     pub fn local_addr(&self) -> Result<Addr, Error> {
         self.channel.local_addr()
@@ -372,35 +893,22 @@ impl EfcpChannel {
         self.channel.peer_addr()
     }
 
-    /// Returns the external address that the remote observed.
-    pub fn external_addr(&self) -> Option<&Addr> {
-        self.external_addr.as_ref()
-    }
-
     /// Returns the channel id.
     pub fn channel(&self) -> u8 {
         self.channel.channel()
     }
-
-    /// Returns the public key of the peer that this channel is connected to.
-    pub fn peer_identity(&self) -> PublicKey {
-        self.remote
-    }
-
-    /// Returns the protocol that this channel has negotiated.
-    pub fn protocol(&self) -> Protocol {
-        self.protocol
-    }
 }
 
 #[async_trait]
-impl Channel for EfcpChannel {
-    type Packet = DtcpPacket<DiscoPacket<DtpPacket>>;
+impl<C: Channel> Channel for EfcpChannel<C> {
+    type Packet = DtcpPacket<DiscoPacket<C::Packet>>;
 
     async fn send(&self, packet: Self::Packet) -> Result<(), Error> {
         self.channel.send(packet).await
     }
 
+    /// Returns a packet with the `fin` flag after the peer closed the stream
+    /// and an error of kind `UnexpectedEof` if the stream was truncated.
     async fn recv(&self) -> Result<Self::Packet, Error> {
         self.channel.recv().await
     }
@@ -409,34 +917,48 @@ impl Channel for EfcpChannel {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::handshake::send_bytes;
     use async_std::task;
+    use bytes::BytesMut;
     use futures::join;
     use rand::rngs::OsRng;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_channel::{LossyChannel, LossyChannelBuilder};
 
-    async fn efcp() -> Result<(), HandshakeError> {
+    async fn efcp(pattern: Pattern) -> Result<(), HandshakeError> {
         let addr = "/ip4/127.0.0.1";
         let protocols = &["/ping/1.0"];
 
         let identity1 = Keypair::generate(&mut OsRng);
-        let socket1 = EfcpSocket::bind(addr, identity1, &["/ping/1.0"]).await?;
+        let mut socket1 = EfcpSocket::bind(addr, identity1, &["/ping/1.0"]).await?;
+        socket1.set_padding(Padding::Bucket(128));
+        socket1.set_pattern(pattern);
+        let identity1 = socket1.identity();
 
         let identity2 = Keypair::generate(&mut OsRng);
-        let socket2 = EfcpSocket::bind(addr, identity2, &["/ping/1.0"]).await?;
+        let mut socket2 = EfcpSocket::bind(addr, identity2, &["/ping/1.0"]).await?;
+        socket2.set_padding(Padding::Mtu(1200));
+        socket2.set_pattern(pattern);
+        let identity2 = socket2.identity();
         let external_addr = socket2.local_addr()?;
 
         /*let dial1 = Dial {
             peer_addr: socket2.local_addr()?,
             channel: 0,
-            remote_public: identity2.public,
-            protocols,
+            remote_public: Some(identity2.public),
+            protocols: Protocols::new(protocols),
         };
         let channel1 = socket1.dial(dial1)?;*/
 
         let dial2 = Dial {
             peer_addr: socket1.local_addr()?,
             channel: 0,
-            remote_public: socket1.identity(),
-            protocols,
+            remote_public: if pattern.requires_remote_public() {
+                Some(identity1)
+            } else {
+                None
+            },
+            protocols: Protocols::new(protocols),
         };
 
         let channel1 = task::spawn(async move { socket1.incoming().await.unwrap().unwrap() });
@@ -448,6 +970,8 @@ mod tests {
         assert_eq!(channel1.protocol(), "/ping/1.0");
         assert_eq!(channel2.protocol(), "/ping/1.0");
         assert_eq!(channel2.external_addr(), Some(&external_addr));
+        assert_eq!(channel1.peer_identity(), identity2);
+        assert_eq!(channel2.peer_identity(), identity1);
 
         channel2.send("ping".into()).await?;
         let msg = channel1.recv().await?;
@@ -457,11 +981,377 @@ mod tests {
         let msg = channel2.recv().await?;
         assert_eq!(msg.payload(), b"pong");
 
+        let (shutdown, eof) = join!(channel2.shutdown_write(), channel1.recv());
+        shutdown?;
+        assert!(eof?.fin());
+
         Ok(())
     }
 
     #[test]
     fn test_efcp() {
-        task::block_on(efcp()).unwrap();
+        task::block_on(efcp(Pattern::XK1sig)).unwrap();
+    }
+
+    #[test]
+    fn test_efcp_ik() {
+        task::block_on(efcp(Pattern::IK)).unwrap();
+    }
+
+    #[test]
+    fn test_efcp_xx() {
+        task::block_on(efcp(Pattern::XX)).unwrap();
+    }
+
+    /// Counts the packets sent over a channel.
+    struct Counted {
+        channel: LossyChannel,
+        sent: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Channel for Counted {
+        type Packet = BytesMut;
+
+        async fn send(&self, packet: Self::Packet) -> Result<(), Error> {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            self.channel.send(packet).await
+        }
+
+        async fn recv(&self) -> Result<Self::Packet, Error> {
+            self.channel.recv().await
+        }
+    }
+
+    /// Returns the number of messages sent by the initiator and the
+    /// responder until both negotiated a protocol.
+    async fn handshake_messages(pattern: Pattern) -> (usize, usize) {
+        let (c1, c2) = LossyChannelBuilder::new(1.0, 0.0).split();
+        let c1 = Counted {
+            channel: c1,
+            sent: AtomicUsize::new(0),
+        };
+        let c2 = Counted {
+            channel: c2,
+            sent: AtomicUsize::new(0),
+        };
+        let identity1 = Keypair::generate(&mut OsRng);
+        let identity2 = Keypair::generate(&mut OsRng);
+        let remote_public = Some(identity2.public);
+        let addr: Addr = "/ip4/127.0.0.1/udp/8000".parse().unwrap();
+        let mut options = Options::default();
+        options.pattern = pattern;
+
+        // The first proposal is rejected.
+        let (channel1, channel2) = join!(
+            EfcpChannel::initiator(
+                c1,
+                &identity1,
+                Protocols::new(&["/ping/2.0", "/ping/1.0"]),
+                options,
+                remote_public,
+                None,
+            ),
+            EfcpChannel::responder(
+                c2,
+                &identity2,
+                Protocols::new(&["/ping/1.0"]),
+                options,
+                addr,
+                None,
+            ),
+        );
+        let (channel1, channel2) = (channel1.unwrap(), channel2.unwrap());
+        assert_eq!(channel1.protocol(), "/ping/1.0");
+        assert_eq!(channel2.protocol(), "/ping/1.0");
+        let sent1 = channel1.channel.sent.load(Ordering::SeqCst);
+        let sent2 = channel2.channel.sent.load(Ordering::SeqCst);
+        (sent1, sent2)
+    }
+
+    #[test]
+    fn test_handshake_round_trips() {
+        task::block_on(async {
+            assert_eq!(handshake_messages(Pattern::XK1sig).await, (2, 1));
+            assert_eq!(handshake_messages(Pattern::IK).await, (1, 1));
+            assert_eq!(handshake_messages(Pattern::XX).await, (2, 1));
+        });
+    }
+
+    #[test]
+    fn test_dtcp_params() {
+        let (c1, c2) = LossyChannelBuilder::new(1.0, 0.0).split();
+        let identity1 = Keypair::generate(&mut OsRng);
+        let identity2 = Keypair::generate(&mut OsRng);
+        let remote_public = Some(identity2.public);
+        let addr: Addr = "/ip4/127.0.0.1/udp/8000".parse().unwrap();
+        let protocols = Protocols::new(&["/ping/1.0"]);
+        let mut options1 = Options::default();
+        options1.dtcp = DtcpParams::new()
+            .set_mpl(Duration::from_secs(60))
+            .set_window(16)
+            .set_congestion_control(CongestionControl::Cubic);
+        let mut options2 = Options::default();
+        options2.dtcp = DtcpParams::new().set_max_retries(5);
+
+        let (channel1, channel2) = task::block_on(async {
+            join!(
+                EfcpChannel::initiator(
+                    c1,
+                    &identity1,
+                    protocols.clone(),
+                    options1,
+                    remote_public,
+                    None
+                ),
+                EfcpChannel::responder(c2, &identity2, protocols.clone(), options2, addr, None),
+            )
+        });
+        let (channel1, channel2) = (channel1.unwrap(), channel2.unwrap());
+        let params = channel1.dtcp_params();
+        assert_eq!(params, channel2.dtcp_params());
+        // The mpl is bounded by the responder's limits.
+        assert_eq!(params.mpl(), Duration::from_secs(10));
+        assert_eq!(params.max_retries(), 5);
+        assert_eq!(params.window(), 16);
+        assert_eq!(params.congestion_control(), CongestionControl::Cubic);
+    }
+
+    #[test]
+    fn test_hostile_dtcp_params() {
+        let (c1, c2) = LossyChannelBuilder::new(1.0, 0.0).split();
+        let identity1 = Keypair::generate(&mut OsRng);
+        let identity2 = Keypair::generate(&mut OsRng);
+        let remote_public = Some(identity2.public);
+        let addr: Addr = "/ip4/127.0.0.1/udp/8000".parse().unwrap();
+        let protocols = Protocols::new(&["/ping/1.0"]);
+
+        // Answers with parameters that keep the channel alive for a week.
+        let responder = async {
+            let mut session = SessionBuilder::new(Pattern::XK1sig.name())
+                .secret(&identity2)
+                .build_responder();
+            let ct = c2.recv().await.unwrap();
+            session.read_message(&ct[..]).unwrap();
+            let hostile = DtcpParams::new()
+                .set_mpl(Duration::from_secs(7 * 24 * 3600))
+                .set_window(1024);
+            let msg = HandshakePacket::new(
+                Some(Message::Accept("/ping/1.0")),
+                Some(hostile),
+                Some(addr),
+            );
+            let ct = session.write_message(&msg.to_bytes().unwrap());
+            send_bytes(&c2, &ct).await.unwrap();
+        };
+        let initiator = EfcpChannel::initiator(
+            c1,
+            &identity1,
+            protocols,
+            Options::default(),
+            remote_public,
+            None,
+        );
+        let (channel1, ()) = task::block_on(async { join!(initiator, responder) });
+        match channel1 {
+            Err(HandshakeError::DtcpParams) => {}
+            _ => panic!("expected the parameters to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_forged() {
+        let (c1, c2) = LossyChannelBuilder::new(1.0, 0.0).split();
+        let identity1 = Keypair::generate(&mut OsRng);
+        let identity2 = Keypair::generate(&mut OsRng);
+        let remote_public = Some(identity2.public);
+        let addr: Addr = "/ip4/127.0.0.1/udp/8000".parse().unwrap();
+        let protocols = Protocols::new(&["/ping/1.0"]);
+
+        let (channel1, channel2) = task::block_on(async {
+            // A forged first message must not break the responder's session.
+            send_bytes(&c1, &[7u8; 100]).await.unwrap();
+            join!(
+                EfcpChannel::initiator(
+                    c1,
+                    &identity1,
+                    protocols.clone(),
+                    Options::default(),
+                    remote_public,
+                    None
+                ),
+                EfcpChannel::responder(c2, &identity2, protocols, Options::default(), addr, None),
+            )
+        });
+        assert_eq!(channel1.unwrap().peer_identity(), identity2.public);
+        assert_eq!(channel2.unwrap().peer_identity(), identity1.public);
+    }
+
+    async fn simultaneous_open() -> Result<(), HandshakeError> {
+        let addr = "/ip4/127.0.0.1";
+
+        let socket1 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), &["/ping/1.0"]).await?;
+        let socket2 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), &["/ping/1.0"]).await?;
+        let dial1 = Dial {
+            peer_addr: socket2.local_addr()?,
+            channel: 3,
+            remote_public: Some(socket2.identity()),
+            protocols: Protocols::new(&["/ping/2.0", "/ping/1.0"]),
+        };
+        let dial2 = Dial {
+            peer_addr: socket1.local_addr()?,
+            channel: 3,
+            remote_public: Some(socket1.identity()),
+            protocols: Protocols::new(&["/ping/1.0", "/ping/2.0"]),
+        };
+
+        let (channel1, channel2) = join!(socket1.dial(&dial1), socket2.dial(&dial2));
+        let (channel1, channel2) = (channel1?, channel2?);
+        assert_eq!(channel1.peer_identity(), socket2.identity());
+        assert_eq!(channel2.peer_identity(), socket1.identity());
+        // The initiator's preference wins.
+        assert!(channel1.protocol() == "/ping/2.0" || channel2.protocol() == "/ping/1.0");
+        assert_eq!(channel1.protocol(), channel2.protocol());
+
+        channel1.send("ping".into()).await?;
+        assert_eq!(channel2.recv().await?.payload(), b"ping");
+        channel2.send("pong".into()).await?;
+        assert_eq!(channel1.recv().await?.payload(), b"pong");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simultaneous_open() {
+        task::block_on(simultaneous_open()).unwrap();
+    }
+
+    async fn access_control() -> Result<(), HandshakeError> {
+        let addr = "/ip4/127.0.0.1";
+        let protocols = &["/ping/1.0"];
+
+        let socket2 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), protocols).await?;
+        let socket3 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), protocols).await?;
+        let identity3 = socket3.identity();
+
+        let mut socket1 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), protocols).await?;
+        socket1.set_access_control(vec![identity3]);
+        let dial = Dial {
+            peer_addr: socket1.local_addr()?,
+            channel: 0,
+            remote_public: Some(socket1.identity()),
+            protocols: Protocols::new(protocols),
+        };
+
+        let channel1 = task::spawn(async move { socket1.incoming().await.unwrap().unwrap() });
+
+        let channel2 = socket2.dial(&dial).await?;
+        let err = channel2.recv().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+        let channel3 = socket3.dial(&dial).await?;
+        let channel1 = channel1.await;
+        assert_eq!(channel1.peer_identity(), identity3);
+
+        channel3.send("ping".into()).await?;
+        let msg = channel1.recv().await?;
+        assert_eq!(msg.payload(), b"ping");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_control() {
+        task::block_on(access_control()).unwrap();
+    }
+
+    async fn listeners() -> Result<(), HandshakeError> {
+        let addr = "/ip4/127.0.0.1";
+        let no_protocols = Vec::<&str>::new();
+
+        let socket1 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), &no_protocols).await?;
+        let socket2 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), &no_protocols).await?;
+        let socket3 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), &no_protocols).await?;
+        let socket4 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), &no_protocols).await?;
+        let dial = |protocol: &str| Dial {
+            peer_addr: socket1.local_addr().unwrap(),
+            channel: 0,
+            remote_public: Some(socket1.identity()),
+            protocols: Protocols::new(&[protocol]),
+        };
+        let (ping, chat, other) = (dial("/ping/1.0"), dial("/chat/1.0"), dial("/other/1.0"));
+
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let channels = task::spawn(async move {
+            let ping = socket1.listen("/ping/1.0");
+            let chat = socket1.listen("/chat/1.0");
+            tx.send(()).unwrap();
+            let ping = ping.incoming().await.unwrap().unwrap();
+            let chat = chat.incoming().await.unwrap().unwrap();
+            let failed = socket1.incoming().await.unwrap().is_err();
+            (ping, chat, failed, socket1.failed_handshakes())
+        });
+        rx.await.unwrap();
+
+        let chat2 = socket3.dial(&chat).await?;
+        match socket4.dial(&other).await {
+            Err(HandshakeError::Negotiation) => {}
+            _ => panic!("expected the protocol to be refused"),
+        }
+        let ping2 = socket2.dial(&ping).await?;
+        let (ping1, chat1, failed, failed_handshakes) = channels.await;
+        assert!(failed);
+        assert_eq!(failed_handshakes, 1);
+        assert_eq!(ping1.protocol(), "/ping/1.0");
+        assert_eq!(ping2.protocol(), "/ping/1.0");
+        assert_eq!(chat1.protocol(), "/chat/1.0");
+        assert_eq!(chat2.protocol(), "/chat/1.0");
+
+        chat2.send("hello".into()).await?;
+        let msg = chat1.recv().await?;
+        assert_eq!(msg.payload(), b"hello");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_listeners() {
+        task::block_on(listeners()).unwrap();
+    }
+
+    async fn silent_peer() -> Result<(), HandshakeError> {
+        let addr = "/ip4/127.0.0.1";
+        let protocols = &["/ping/1.0"];
+
+        let mut socket1 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), protocols).await?;
+        socket1.set_handshake_timeout(Duration::from_secs(60));
+        let socket2 = EfcpSocket::bind(addr, Keypair::generate(&mut OsRng), protocols).await?;
+        let identity2 = socket2.identity();
+        let peer_addr = socket1.local_addr()?;
+        let dial = Dial {
+            peer_addr,
+            channel: 0,
+            remote_public: Some(socket1.identity()),
+            protocols: Protocols::new(protocols),
+        };
+        let channel1 = task::spawn(async move { socket1.incoming().await.unwrap() });
+
+        // Opens a channel and never answers.
+        let socket3 = DtpSocket::bind(addr).await?;
+        let silent = socket3.outgoing(peer_addr, 0)?;
+        send_bytes(&silent, b"hello").await?;
+
+        let channel2 = socket2.dial(&dial).await?;
+        let channel1 = channel1.await?;
+        assert_eq!(channel1.peer_identity(), identity2);
+        channel2.send("ping".into()).await?;
+        assert_eq!(channel1.recv().await?.payload(), b"ping");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_silent_peer() {
+        task::block_on(silent_peer()).unwrap();
     }
 }
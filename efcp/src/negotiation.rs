@@ -1,77 +1,146 @@
 use crate::error::HandshakeError;
+use core::cmp::Ordering;
+use disco::ed25519::PublicKey;
+use std::sync::Arc;
+
+/// Protocol identifier.
+pub type Protocol = Arc<str>;
+
+/// List of protocol identifiers in order of preference.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Protocols(Arc<[Protocol]>);
+
+impl Protocols {
+    /// Creates a new list of protocol identifiers.
+    pub fn new<I: IntoIterator<Item = S>, S: AsRef<str>>(protocols: I) -> Self {
+        let protocols: Vec<Protocol> = protocols
+            .into_iter()
+            .map(|protocol| protocol.as_ref().into())
+            .collect();
+        Self(protocols.into())
+    }
+}
+
+impl From<Vec<Protocol>> for Protocols {
+    fn from(protocols: Vec<Protocol>) -> Self {
+        Self(protocols.into())
+    }
+}
+
+impl core::ops::Deref for Protocols {
+    type Target = [Protocol];
 
-pub type Protocol = &'static str;
-pub type Protocols = &'static [Protocol];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
 
+/// Negotiation message. Received messages borrow the protocol identifiers
+/// from the packet, sent messages share them with the `Negotiation`.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub enum Message<'a> {
-    Propose(&'a str),
-    Accept,
+pub enum Message<S> {
+    /// All protocols of the initiator in order of preference and a random
+    /// nonce to select the roles when both peers initiate.
+    Propose { protocols: Vec<S>, nonce: u64 },
+    /// The protocol selected by the responder.
+    Accept(S),
+    /// No protocol in common.
     Fail,
 }
 
 pub struct Negotiation {
     protocols: Protocols,
-    tried: usize,
+    nonce: u64,
     started: bool,
-    proposed: Option<&'static str>,
-    accepted: Option<&'static str>,
+    accepted: Option<Protocol>,
     finished: bool,
 }
 
 impl Negotiation {
     pub fn new(protocols: Protocols) -> Self {
+        Self::with_nonce(protocols, rand::random())
+    }
+
+    fn with_nonce(protocols: Protocols, nonce: u64) -> Self {
         Self {
             protocols,
+            nonce,
             started: false,
-            tried: 0,
-            proposed: None,
             accepted: None,
             finished: false,
         }
     }
 
-    fn propose(&mut self) -> Message<'static> {
-        let i = self.tried;
-        self.tried += 1;
-        if let Some(protocol) = self.protocols.get(i) {
-            self.proposed = Some(protocol);
-            Message::Propose(protocol)
-        } else {
-            self.finished = true;
-            Message::Fail
+    /// Proposes the whole protocol list, so that the negotiation completes
+    /// in a single round trip.
+    pub fn initiate(&mut self) -> Message<Protocol> {
+        assert!(!self.started);
+        self.started = true;
+        Message::Propose {
+            protocols: self.protocols.to_vec(),
+            nonce: self.nonce,
         }
     }
 
-    pub fn initiate(&mut self) -> Message<'static> {
-        assert!(!self.started);
-        self.started = true;
-        self.propose()
+    fn find(&self, protocol: &str) -> Option<Protocol> {
+        self.protocols
+            .iter()
+            .find(|p| p.as_ref() == protocol)
+            .cloned()
     }
 
-    pub fn message(&mut self, msg: &Message) -> Result<Option<Message<'static>>, HandshakeError> {
-        self.started = true;
+    /// Returns `true` if we take the responder role after both peers
+    /// initiated with the proposal of `nonce`. The peer with the larger
+    /// nonce responds. If the nonces are equal the peer with the larger
+    /// public key responds, which requires the public key of the peer.
+    pub fn responds_to(
+        &self,
+        nonce: u64,
+        local: &PublicKey,
+        remote: Option<&PublicKey>,
+    ) -> Result<bool, HandshakeError> {
+        match self.nonce.cmp(&nonce) {
+            Ordering::Greater => Ok(true),
+            Ordering::Less => Ok(false),
+            Ordering::Equal => match remote {
+                Some(remote) if local != remote => Ok(local.as_bytes() > remote.as_bytes()),
+                _ => Err(HandshakeError::ProtocolError),
+            },
+        }
+    }
+
+    pub fn message<S: AsRef<str>>(
+        &mut self,
+        msg: &Message<S>,
+    ) -> Result<Option<Message<Protocol>>, HandshakeError> {
+        if self.finished {
+            return Err(HandshakeError::ProtocolError);
+        }
         match msg {
-            Message::Propose(protocol) => {
-                if self.accepted.is_some() {
+            Message::Propose { protocols, .. } => {
+                if self.started {
+                    // When both peers initiate one of them takes the
+                    // responder role with a new negotiation.
                     return Err(HandshakeError::ProtocolError);
                 }
-
-                self.accepted = self
-                    .protocols
-                    .into_iter()
-                    .find(|p| *p == protocol)
-                    .map(|p| *p);
-
-                if self.accepted.is_some() {
-                    self.finished = true;
-                    Ok(Some(Message::Accept))
-                } else {
-                    Ok(Some(self.propose()))
+                self.started = true;
+                self.finished = true;
+                // The initiator's preference wins.
+                self.accepted = protocols
+                    .iter()
+                    .filter_map(|p| self.find(p.as_ref()))
+                    .next();
+                match self.accepted.clone() {
+                    Some(protocol) => Ok(Some(Message::Accept(protocol))),
+                    None => Ok(Some(Message::Fail)),
                 }
             }
-            Message::Accept => {
-                self.accepted = self.proposed.take();
+            Message::Accept(protocol) => {
+                if !self.started {
+                    return Err(HandshakeError::ProtocolError);
+                }
+                let protocol = self.find(protocol.as_ref());
+                self.accepted = Some(protocol.ok_or(HandshakeError::ProtocolError)?);
                 self.finished = true;
                 Ok(None)
             }
@@ -95,15 +164,23 @@ impl Negotiation {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use disco::ed25519::Keypair;
+    use rand::rngs::OsRng;
 
     #[test]
     fn single_proto_common() {
-        let mut n1 = Negotiation::new(&["/ping/1.0"]);
-        let mut n2 = Negotiation::new(&["/ping/1.0"]);
+        let mut n1 = Negotiation::new(Protocols::new(&["/ping/1.0"]));
+        let mut n2 = Negotiation::new(Protocols::new(&["/ping/1.0"]));
         let m1 = n1.initiate();
-        assert_eq!(m1, Message::Propose("/ping/1.0"));
+        assert_eq!(
+            m1,
+            Message::Propose {
+                protocols: vec!["/ping/1.0".into()],
+                nonce: n1.nonce,
+            }
+        );
         let m2 = n2.message(&m1).unwrap().unwrap();
-        assert_eq!(m2, Message::Accept);
+        assert_eq!(m2, Message::Accept("/ping/1.0".into()));
         let m3 = n1.message(&m2).unwrap();
         assert_eq!(m3, None);
 
@@ -111,27 +188,30 @@ mod tests {
         assert!(n2.is_finished());
 
         let p1 = n1.into_protocol();
-        assert_eq!(p1, Some("/ping/1.0"));
+        assert_eq!(p1, Some("/ping/1.0".into()));
         let p2 = n2.into_protocol();
         assert_eq!(p1, p2);
     }
 
     #[test]
     fn no_proto_common() {
-        let mut n1 = Negotiation::new(&["/ping/1.0"]);
-        let mut n2 = Negotiation::new(&["/ping/2.0"]);
+        let mut n1 = Negotiation::new(Protocols::new(&["/ping/1.0"]));
+        let mut n2 = Negotiation::new(Protocols::new(&["/ping/2.0"]));
 
         let m1 = n1.initiate();
-        assert_eq!(m1, Message::Propose("/ping/1.0"));
+        assert_eq!(
+            m1,
+            Message::Propose {
+                protocols: vec!["/ping/1.0".into()],
+                nonce: n1.nonce,
+            }
+        );
 
         let m2 = n2.message(&m1).unwrap().unwrap();
-        assert_eq!(m2, Message::Propose("/ping/2.0"));
-
-        let m3 = n1.message(&m2).unwrap().unwrap();
-        assert_eq!(m3, Message::Fail);
+        assert_eq!(m2, Message::Fail);
 
-        let m4 = n2.message(&m3).unwrap();
-        assert_eq!(m4, None);
+        let m3 = n1.message(&m2).unwrap();
+        assert_eq!(m3, None);
 
         assert!(n1.is_finished());
         assert!(n2.is_finished());
@@ -144,56 +224,105 @@ mod tests {
 
     #[test]
     fn one_proto_common() {
-        let mut n1 = Negotiation::new(&["/ping/2.0", "/ping/1.0"]);
-        let mut n2 = Negotiation::new(&["/ping/1.0"]);
+        let mut n1 = Negotiation::new(Protocols::new(&["/ping/2.0", "/ping/1.0"]));
+        let mut n2 = Negotiation::new(Protocols::new(&["/ping/1.0"]));
 
         let m1 = n1.initiate();
-        assert_eq!(m1, Message::Propose("/ping/2.0"));
+        assert_eq!(
+            m1,
+            Message::Propose {
+                protocols: vec!["/ping/2.0".into(), "/ping/1.0".into()],
+                nonce: n1.nonce,
+            }
+        );
 
         let m2 = n2.message(&m1).unwrap().unwrap();
-        assert_eq!(m2, Message::Propose("/ping/1.0"));
+        assert_eq!(m2, Message::Accept("/ping/1.0".into()));
 
-        let m3 = n1.message(&m2).unwrap().unwrap();
-        assert_eq!(m3, Message::Accept);
-
-        let m4 = n2.message(&m3).unwrap();
-        assert_eq!(m4, None);
+        let m3 = n1.message(&m2).unwrap();
+        assert_eq!(m3, None);
 
         assert!(n1.is_finished());
         assert!(n2.is_finished());
 
         let p1 = n1.into_protocol();
-        assert_eq!(p1, Some("/ping/1.0"));
+        assert_eq!(p1, Some("/ping/1.0".into()));
         let p2 = n2.into_protocol();
         assert_eq!(p1, p2);
     }
 
-    /*#[test]
-    fn both_initiate() {
-        let mut n1 = Negotiation::new(&["/ping/2.0", "/ping/1.0"]);
-        let mut n2 = Negotiation::new(&["/ping/1.0", "/ping/2.0"]);
+    #[test]
+    fn initiator_preference() {
+        let mut n1 = Negotiation::new(Protocols::new(&["/ping/2.0", "/ping/1.0"]));
+        let mut n2 = Negotiation::new(Protocols::new(&["/ping/1.0", "/ping/2.0"]));
 
         let m1 = n1.initiate();
-        assert_eq!(m1, Message::Propose("/ping/2.0"));
+        let m2 = n2.message(&m1).unwrap().unwrap();
+        assert_eq!(m2, Message::Accept("/ping/2.0".into()));
+        assert_eq!(n1.message(&m2).unwrap(), None);
 
-        let m2 = n2.initiate();
-        assert_eq!(m2, Message::Propose("/ping/1.0"));
+        assert_eq!(n1.into_protocol(), Some("/ping/2.0".into()));
+        assert_eq!(n2.into_protocol(), Some("/ping/2.0".into()));
+    }
 
-        let m3 = n1.message(m2).unwrap().unwrap();
-        assert_eq!(m3, Message::Accept("/ping/1.0"));
+    #[test]
+    fn accept_not_proposed() {
+        let mut n1 = Negotiation::new(Protocols::new(&["/ping/1.0"]));
+        n1.initiate();
+        assert!(n1.message(&Message::Accept("/ping/2.0")).is_err());
+    }
 
-        let m4 = n2.message(m1).unwrap().unwrap();
-        assert_eq!(m4, Message::Accept("/ping/2.0"));
+    #[test]
+    fn owned_protocols() {
+        let protocols = vec![String::from("/ping/2.0"), String::from("/ping/1.0")];
+        let mut n1 = Negotiation::new(Protocols::new(&protocols));
+        let mut n2 = Negotiation::new(Protocols::new(vec!["/ping/1.0"]));
 
-        let m5 = n1.message(m4).unwrap().unwrap();
-        let m6 = n2.message(m3).unwrap().unwrap()
+        let m1 = n1.initiate();
+        let m2 = n2.message(&m1).unwrap().unwrap();
+        assert_eq!(n1.message(&m2).unwrap(), None);
 
-        assert!(n1.is_finished());
-        assert!(n2.is_finished());
+        assert_eq!(n1.into_protocol(), Some("/ping/1.0".into()));
+        assert_eq!(n2.into_protocol(), Some("/ping/1.0".into()));
+    }
+
+    #[test]
+    fn both_initiate() {
+        let local = Keypair::generate(&mut OsRng).public;
+        let protocols2 = Protocols::new(&["/ping/1.0", "/ping/2.0"]);
+        let mut n1 = Negotiation::with_nonce(Protocols::new(&["/ping/2.0", "/ping/1.0"]), 1);
+        let mut n2 = Negotiation::with_nonce(protocols2.clone(), 2);
+
+        let m1 = n1.initiate();
+        let m2 = n2.initiate();
+
+        // n2 has the larger nonce and takes the responder role.
+        assert!(!n1.responds_to(2, &local, None).unwrap());
+        assert!(n2.responds_to(1, &local, None).unwrap());
+        assert!(n1.message(&m2).is_err());
+        let mut n2 = Negotiation::new(protocols2);
+
+        let m3 = n2.message(&m1).unwrap().unwrap();
+        assert_eq!(m3, Message::Accept("/ping/2.0".into()));
+        assert_eq!(n1.message(&m3).unwrap(), None);
 
         let p1 = n1.into_protocol();
-        assert_eq!(p1, Some("/ping/1.0"));
+        assert_eq!(p1, Some("/ping/2.0".into()));
         let p2 = n2.into_protocol();
         assert_eq!(p1, p2);
-    }*/
+    }
+
+    #[test]
+    fn both_initiate_equal_nonces() {
+        let key1 = Keypair::generate(&mut OsRng).public;
+        let key2 = Keypair::generate(&mut OsRng).public;
+        let n1 = Negotiation::with_nonce(Protocols::new(&["/ping/1.0"]), 1);
+        let n2 = Negotiation::with_nonce(Protocols::new(&["/ping/1.0"]), 1);
+
+        // The public keys break the tie.
+        let r1 = n1.responds_to(1, &key1, Some(&key2)).unwrap();
+        let r2 = n2.responds_to(1, &key2, Some(&key1)).unwrap();
+        assert_ne!(r1, r2);
+        assert!(n1.responds_to(1, &key1, None).is_err());
+    }
 }
@@ -1,100 +1,202 @@
 use crate::negotiation::Message;
+use crate::params::DtcpParams;
 use addr::Addr;
+use byteorder::{BigEndian, ByteOrder};
+use dtcp::CongestionControl;
 use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
 
+/// Flag of the type byte if the packet contains dtcp parameters.
+const PARAMS: u8 = 0x20;
+/// Flag of the type byte if the packet contains an external address.
+const ADDR: u8 = 0x10;
+/// Length of the encoded dtcp parameters.
+const PARAMS_LEN: usize = 12;
+
+/// Handshake payload. Parsed packets borrow the protocol identifiers from
+/// the decrypted buffer.
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub struct HandshakePacket<'a> {
-    negotiate: Option<Message<'a>>,
+pub struct HandshakePacket<S> {
+    negotiate: Option<Message<S>>,
+    params: Option<DtcpParams>,
     external_addr: Option<Addr>,
 }
 
-impl<'a> HandshakePacket<'a> {
-    pub fn new(negotiate: Option<Message<'static>>, external_addr: Option<Addr>) -> Self {
-        Self {
-            negotiate,
-            external_addr,
-        }
+fn invalid() -> Error {
+    Error::new(ErrorKind::Other, "invalid handshake packet")
+}
+
+fn read_str<'a>(bytes: &'a [u8], i: &mut usize) -> Result<&'a str> {
+    let len = *bytes.get(*i).ok_or_else(invalid)? as usize;
+    *i += 1;
+    let i2 = *i + len;
+    if bytes.len() < i2 {
+        return Err(invalid());
+    }
+    let s = core::str::from_utf8(&bytes[*i..i2]).map_err(|_| invalid())?;
+    *i = i2;
+    Ok(s)
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) -> Result<()> {
+    let s = s.as_bytes();
+    let len = s.len();
+    if len > core::u8::MAX as usize {
+        return Err(invalid());
     }
+    bytes.push(len as u8);
+    bytes.extend_from_slice(s);
+    Ok(())
+}
 
-    fn invalid() -> Error {
-        Error::new(ErrorKind::Other, "invalid handshake packet")
+fn read_params(bytes: &[u8], i: &mut usize) -> Result<DtcpParams> {
+    if bytes.len() < *i + PARAMS_LEN {
+        return Err(invalid());
+    }
+    let bytes = &bytes[*i..(*i + PARAMS_LEN)];
+    let window = BigEndian::read_u16(&bytes[9..11]);
+    if window == 0 {
+        return Err(invalid());
     }
+    let congestion = match bytes[11] {
+        0 => CongestionControl::NewReno,
+        1 => CongestionControl::Cubic,
+        _ => return Err(invalid()),
+    };
+    *i += PARAMS_LEN;
+    Ok(DtcpParams {
+        mpl: Duration::from_millis(BigEndian::read_u32(&bytes[0..4]) as u64),
+        ack: Duration::from_millis(BigEndian::read_u32(&bytes[4..8]) as u64),
+        max_retries: bytes[8],
+        window,
+        congestion,
+    })
+}
+
+fn write_params(bytes: &mut Vec<u8>, params: &DtcpParams) {
+    let millis = |duration: Duration| duration.as_millis().min(core::u32::MAX as u128) as u32;
+    let mut buf = [0; PARAMS_LEN];
+    BigEndian::write_u32(&mut buf[0..4], millis(params.mpl));
+    BigEndian::write_u32(&mut buf[4..8], millis(params.ack));
+    buf[8] = params.max_retries;
+    BigEndian::write_u16(&mut buf[9..11], params.window);
+    buf[11] = match params.congestion {
+        CongestionControl::NewReno => 0,
+        CongestionControl::Cubic => 1,
+    };
+    bytes.extend_from_slice(&buf);
+}
 
+impl<'a> HandshakePacket<&'a str> {
     pub fn from_bytes(bytes: &'a [u8]) -> Result<Self> {
         let mut i = 0;
         if bytes.get(i).is_none() {
-            return Err(Self::invalid());
+            return Err(invalid());
         }
         let ty = bytes[0];
         i += 1;
-        let contains_addr = ty >> 4 > 0;
+        let contains_params = ty & PARAMS > 0;
+        let contains_addr = ty & ADDR > 0;
         let negotiate = match ty & 0xf {
             0 => None,
             1 => {
-                if bytes.get(i).is_none() {
-                    return Err(Self::invalid());
+                if bytes.len() < i + 9 {
+                    return Err(invalid());
                 }
-                let len = bytes[i] as usize;
-                i += 1;
-                let i2 = i + len;
-                if bytes.len() < i2 {
-                    return Err(Self::invalid());
+                let nonce = BigEndian::read_u64(&bytes[i..(i + 8)]);
+                let count = bytes[i + 8] as usize;
+                i += 9;
+                let mut protocols = Vec::with_capacity(count);
+                for _ in 0..count {
+                    protocols.push(read_str(bytes, &mut i)?);
                 }
-                let protocol = core::str::from_utf8(&bytes[i..i2]).map_err(|_| Self::invalid())?;
-                i = i2;
-                Some(Message::Propose(protocol))
+                Some(Message::Propose { protocols, nonce })
             }
-            2 => Some(Message::Accept),
+            2 => Some(Message::Accept(read_str(bytes, &mut i)?)),
             3 => Some(Message::Fail),
-            _ => return Err(Self::invalid()),
+            _ => return Err(invalid()),
+        };
+        let params = if contains_params {
+            Some(read_params(bytes, &mut i)?)
+        } else {
+            None
         };
         let external_addr = if contains_addr {
             if bytes.get(i).is_none() {
-                return Err(Self::invalid());
+                return Err(invalid());
             }
             let len = bytes[i] as usize;
             i += 1;
             let i2 = i + len;
             if bytes.len() < i2 {
-                return Err(Self::invalid());
+                return Err(invalid());
             }
-            let external_addr = core::str::from_utf8(&bytes[i..i2]).map_err(|_| Self::invalid())?;
-            let external_addr = external_addr.parse().map_err(|_| Self::invalid())?;
+            let external_addr = core::str::from_utf8(&bytes[i..i2]).map_err(|_| invalid())?;
+            let external_addr = external_addr.parse().map_err(|_| invalid())?;
             Some(external_addr)
         } else {
             None
         };
         Ok(Self {
             negotiate,
+            params,
             external_addr,
         })
     }
+}
+
+impl<S: AsRef<str>> HandshakePacket<S> {
+    pub fn new(
+        negotiate: Option<Message<S>>,
+        params: Option<DtcpParams>,
+        external_addr: Option<Addr>,
+    ) -> Self {
+        Self {
+            negotiate,
+            params,
+            external_addr,
+        }
+    }
 
-    pub fn negotiate(&mut self) -> Option<Message> {
+    pub fn negotiate(&mut self) -> Option<Message<S>> {
         self.negotiate.take()
     }
 
+    pub fn params(&mut self) -> Option<DtcpParams> {
+        self.params.take()
+    }
+
     pub fn external_addr(&mut self) -> Option<Addr> {
         self.external_addr.take()
     }
 
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
         let mut bytes = Vec::new();
-        match self.negotiate {
+        match &self.negotiate {
             None => bytes.push(0),
-            Some(Message::Propose(protocol)) => {
+            Some(Message::Propose { protocols, nonce }) => {
                 bytes.push(1);
-                let protocol = protocol.as_bytes();
-                let len = protocol.len();
-                if len > core::u8::MAX as usize {
-                    return Err(Self::invalid());
+                if protocols.len() > core::u8::MAX as usize {
+                    return Err(invalid());
+                }
+                let mut buf = [0; 8];
+                BigEndian::write_u64(&mut buf, *nonce);
+                bytes.extend_from_slice(&buf);
+                bytes.push(protocols.len() as u8);
+                for protocol in protocols {
+                    write_str(&mut bytes, protocol.as_ref())?;
                 }
-                bytes.push(len as u8);
-                bytes.extend_from_slice(protocol);
             }
-            Some(Message::Accept) => bytes.push(2),
+            Some(Message::Accept(protocol)) => {
+                bytes.push(2);
+                write_str(&mut bytes, protocol.as_ref())?;
+            }
             Some(Message::Fail) => bytes.push(3),
         }
+        if let Some(params) = &self.params {
+            write_params(&mut bytes, params);
+            bytes[0] |= PARAMS;
+        }
         match &self.external_addr {
             None => {}
             Some(addr) => {
@@ -102,11 +204,11 @@ impl<'a> HandshakePacket<'a> {
                 let addr = addr.as_bytes();
                 let len = addr.len();
                 if len > core::u8::MAX as usize {
-                    return Err(Self::invalid());
+                    return Err(invalid());
                 }
                 bytes.push(len as u8);
                 bytes.extend_from_slice(addr);
-                bytes[0] |= 0xf0;
+                bytes[0] |= ADDR;
             }
         }
         Ok(bytes)
@@ -117,8 +219,28 @@ impl<'a> HandshakePacket<'a> {
 mod tests {
     use super::*;
 
-    fn check(msg: Option<Message<'static>>, addr: Option<Addr>) {
-        let packet = HandshakePacket::new(msg, addr);
+    fn propose(protocols: Vec<&'static str>) -> Message<&'static str> {
+        Message::Propose {
+            protocols,
+            nonce: 42,
+        }
+    }
+
+    fn check(msg: Option<Message<&'static str>>, addr: Option<Addr>) {
+        check_params(msg.clone(), None, addr.clone());
+        let params = DtcpParams::new()
+            .set_mpl(Duration::from_millis(1500))
+            .set_window(256)
+            .set_congestion_control(CongestionControl::Cubic);
+        check_params(msg, Some(params), addr);
+    }
+
+    fn check_params(
+        msg: Option<Message<&'static str>>,
+        params: Option<DtcpParams>,
+        addr: Option<Addr>,
+    ) {
+        let packet = HandshakePacket::new(msg, params, addr);
         let bytes = packet.to_bytes().unwrap();
         let p2 = HandshakePacket::from_bytes(&bytes).unwrap();
         assert_eq!(p2, packet);
@@ -130,14 +252,35 @@ mod tests {
         let addrv6 = "/ip6/::1".parse().unwrap();
         let protocol = "/ping/1.0";
         check(None, None);
-        check(Some(Message::Propose(protocol)), None);
-        check(Some(Message::Accept), None);
+        check(Some(propose(vec![protocol])), None);
+        check(Some(propose(vec![protocol, "/ping/2.0"])), None);
+        check(Some(propose(vec![])), None);
+        check(Some(Message::Accept(protocol)), None);
         check(Some(Message::Fail), None);
         check(None, Some(addrv4));
         check(None, Some(addrv6));
-        check(Some(Message::Propose(protocol)), Some(addrv4));
-        check(Some(Message::Propose(protocol)), Some(addrv4));
-        check(Some(Message::Accept), Some(addrv6));
+        check(Some(propose(vec![protocol])), Some(addrv4));
+        check(Some(propose(vec![protocol])), Some(addrv4));
+        check(Some(Message::Accept(protocol)), Some(addrv6));
         check(Some(Message::Fail), Some(addrv4));
     }
+
+    #[test]
+    fn test_invalid_params() {
+        let params = DtcpParams::new();
+        let bytes = HandshakePacket::<&str>::new(None, Some(params), None)
+            .to_bytes()
+            .unwrap();
+        // Zero window.
+        let mut zero_window = bytes.clone();
+        zero_window[10] = 0;
+        zero_window[11] = 0;
+        assert!(HandshakePacket::from_bytes(&zero_window).is_err());
+        // Unknown congestion control algorithm.
+        let mut unknown = bytes.clone();
+        unknown[12] = 2;
+        assert!(HandshakePacket::from_bytes(&unknown).is_err());
+        // Truncated.
+        assert!(HandshakePacket::from_bytes(&bytes[..PARAMS_LEN]).is_err());
+    }
 }
@@ -0,0 +1,223 @@
+//! DTCP parameter negotiation.
+//!
+//! The initiator proposes its parameters in the first handshake message and
+//! the responder answers with the agreed parameters, so both peers build
+//! their DTCP channels with the same parameters.
+use dtcp::{CongestionControl, DtcpBuilder};
+use std::time::Duration;
+
+/// Parameters of the DTCP channels of a socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DtcpParams {
+    pub(crate) mpl: Duration,
+    pub(crate) ack: Duration,
+    pub(crate) max_retries: u8,
+    pub(crate) window: u16,
+    pub(crate) congestion: CongestionControl,
+}
+
+impl DtcpParams {
+    /// Creates new `DtcpParams` with the defaults of `DtcpBuilder`.
+    pub fn new() -> Self {
+        Self {
+            mpl: Duration::from_millis(1000),
+            ack: Duration::from_millis(100),
+            max_retries: 3,
+            window: 64,
+            congestion: CongestionControl::default(),
+        }
+    }
+
+    /// Sets the maximum packet lifetime.
+    pub fn set_mpl(mut self, mpl: Duration) -> Self {
+        self.mpl = mpl;
+        self
+    }
+
+    /// Sets the maximum time to ack.
+    pub fn set_ack(mut self, ack: Duration) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    /// Sets the maximum number of retries.
+    pub fn set_max_retries(mut self, max_retries: u8) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the size of the receive window in packets.
+    pub fn set_window(mut self, window: u16) -> Self {
+        assert!(window > 0);
+        self.window = window;
+        self
+    }
+
+    /// Sets the congestion control algorithm. Defaults to NewReno.
+    pub fn set_congestion_control(mut self, congestion: CongestionControl) -> Self {
+        self.congestion = congestion;
+        self
+    }
+
+    /// Returns the maximum packet lifetime.
+    pub fn mpl(&self) -> Duration {
+        self.mpl
+    }
+
+    /// Returns the maximum time to ack.
+    pub fn ack(&self) -> Duration {
+        self.ack
+    }
+
+    /// Returns the maximum number of retries.
+    pub fn max_retries(&self) -> u8 {
+        self.max_retries
+    }
+
+    /// Returns the size of the receive window in packets.
+    pub fn window(&self) -> u16 {
+        self.window
+    }
+
+    /// Returns the congestion control algorithm.
+    pub fn congestion_control(&self) -> CongestionControl {
+        self.congestion
+    }
+
+    /// Returns the parameters agreed by the responder. The timers and the
+    /// number of retries tolerate the slower peer, the window fits the
+    /// smaller receive buffer and the initiator's congestion control
+    /// algorithm wins. The result is bounded by the responder's limits.
+    pub(crate) fn agree(&self, proposed: &DtcpParams, limits: &DtcpLimits) -> Self {
+        Self {
+            mpl: self.mpl.max(proposed.mpl).min(limits.max_mpl),
+            ack: self.ack.max(proposed.ack).min(limits.max_ack),
+            max_retries: self
+                .max_retries
+                .max(proposed.max_retries)
+                .min(limits.max_retries),
+            window: self.window.min(proposed.window),
+            congestion: proposed.congestion,
+        }
+    }
+
+    /// Returns `true` if the initiator accepts the parameters agreed by the
+    /// responder. They must follow the rules of `agree` and exceed neither
+    /// the initiator's proposal nor it's limits.
+    pub(crate) fn accepts(&self, agreed: &DtcpParams, limits: &DtcpLimits) -> bool {
+        agreed.window <= self.window
+            && agreed.congestion == self.congestion
+            && agreed.mpl <= self.mpl.max(limits.max_mpl)
+            && agreed.ack <= self.ack.max(limits.max_ack)
+            && agreed.max_retries <= self.max_retries.max(limits.max_retries)
+    }
+
+    /// Returns a `DtcpBuilder` with the parameters.
+    pub(crate) fn builder(&self) -> DtcpBuilder {
+        DtcpBuilder::new()
+            .set_mpl(self.mpl)
+            .set_ack(self.ack)
+            .set_max_retries(self.max_retries)
+            .set_window(self.window)
+            .set_congestion_control(self.congestion)
+    }
+}
+
+impl Default for DtcpParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Limits of the DTCP parameters a responder agrees to and an initiator
+/// accepts.
+///
+/// Large timers and retry counts keep the state of a channel alive for a
+/// long time after the peer is gone.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DtcpLimits {
+    max_mpl: Duration,
+    max_ack: Duration,
+    max_retries: u8,
+}
+
+impl DtcpLimits {
+    /// Creates new `DtcpLimits`.
+    pub fn new() -> Self {
+        Self {
+            max_mpl: Duration::from_secs(10),
+            max_ack: Duration::from_secs(1),
+            max_retries: 10,
+        }
+    }
+
+    /// Sets the largest maximum packet lifetime.
+    pub fn set_max_mpl(mut self, max_mpl: Duration) -> Self {
+        self.max_mpl = max_mpl;
+        self
+    }
+
+    /// Sets the largest maximum time to ack.
+    pub fn set_max_ack(mut self, max_ack: Duration) -> Self {
+        self.max_ack = max_ack;
+        self
+    }
+
+    /// Sets the largest maximum number of retries.
+    pub fn set_max_retries(mut self, max_retries: u8) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+}
+
+impl Default for DtcpLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agree() {
+        let responder = DtcpParams::new();
+        let initiator = DtcpParams::new()
+            .set_mpl(Duration::from_secs(2))
+            .set_ack(Duration::from_millis(50))
+            .set_max_retries(20)
+            .set_window(128)
+            .set_congestion_control(CongestionControl::Cubic);
+        let params = responder.agree(&initiator, &DtcpLimits::new());
+        assert_eq!(params.mpl(), Duration::from_secs(2));
+        assert_eq!(params.ack(), Duration::from_millis(100));
+        assert_eq!(params.max_retries(), 10);
+        assert_eq!(params.window(), 64);
+        assert_eq!(params.congestion_control(), CongestionControl::Cubic);
+
+        let limits = DtcpLimits::new().set_max_mpl(Duration::from_millis(500));
+        let params = responder.agree(&initiator, &limits);
+        assert_eq!(params.mpl(), Duration::from_millis(500));
+        assert!(initiator.accepts(&params, &DtcpLimits::new()));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let proposal = DtcpParams::new();
+        let limits = DtcpLimits::new();
+        assert!(proposal.accepts(&proposal, &limits));
+        assert!(proposal.accepts(&proposal.set_window(16), &limits));
+        assert!(!proposal.accepts(&proposal.set_window(1024), &limits));
+        let mpl = proposal.set_mpl(Duration::from_secs(3600));
+        assert!(!proposal.accepts(&mpl, &limits));
+        let ack = proposal.set_ack(Duration::from_secs(3600));
+        assert!(!proposal.accepts(&ack, &limits));
+        assert!(!proposal.accepts(&proposal.set_max_retries(255), &limits));
+        let cubic = proposal.set_congestion_control(CongestionControl::Cubic);
+        assert!(!proposal.accepts(&cubic, &limits));
+        // The initiator's own proposal is accepted beyond it's limits.
+        let slow = proposal.set_mpl(Duration::from_secs(60));
+        assert!(slow.accepts(&slow, &limits));
+    }
+}
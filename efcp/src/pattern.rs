@@ -0,0 +1,42 @@
+/// Noise handshake pattern used to establish a channel.
+///
+/// The initiator and responder must use the same pattern.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Pattern {
+    /// The initiator knows the responder's public key. The initiator's
+    /// identity is sent in the last message and both identities are proven
+    /// with signatures.
+    XK1sig,
+    /// The initiator knows the responder's public key and sends its own
+    /// identity in the first message, completing the handshake in a single
+    /// round trip.
+    IK,
+    /// Neither side knows the other's public key in advance. The identities
+    /// are exchanged during the handshake.
+    XX,
+}
+
+impl Pattern {
+    /// Returns the name of the pattern.
+    pub fn name(self) -> &'static str {
+        match self {
+            Pattern::XK1sig => "XK1sig",
+            Pattern::IK => "IK",
+            Pattern::XX => "XX",
+        }
+    }
+
+    /// Returns `true` if the initiator needs the responder's public key.
+    pub fn requires_remote_public(self) -> bool {
+        match self {
+            Pattern::XK1sig | Pattern::IK => true,
+            Pattern::XX => false,
+        }
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern::XK1sig
+    }
+}
@@ -0,0 +1,248 @@
+//! Session rekeying.
+//!
+//! A rekey is an ephemeral `NN` handshake tunneled through the current
+//! session, so it is authenticated by the current keys. The initiator
+//! switches to the new keys when it receives the response, the responder
+//! when it receives the first packet encrypted with the new keys. The most
+//! significant bit of the nonce selects the keys of a packet.
+use disco::{ReadError, SessionBuilder, StatelessTransportState};
+use std::time::{Duration, Instant};
+
+/// Nonce bit selecting the keys of a packet.
+pub(crate) const PHASE: u64 = 1 << 63;
+
+/// Interval at which an unanswered rekey request is repeated.
+const RETRY: Duration = Duration::from_secs(1);
+
+/// Rekeying policy of encrypted channels.
+///
+/// A rekey is started when any of the limits is reached.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RekeyPolicy {
+    max_bytes: u64,
+    max_messages: u64,
+    max_age: Duration,
+    grace: Duration,
+}
+
+impl RekeyPolicy {
+    /// Creates a new `RekeyPolicy`.
+    pub fn new() -> Self {
+        Self {
+            max_bytes: 1 << 30,
+            max_messages: 1 << 20,
+            max_age: Duration::from_secs(3600),
+            grace: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets the number of bytes sent before rekeying.
+    pub fn set_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Sets the number of messages sent before rekeying.
+    pub fn set_max_messages(mut self, max_messages: u64) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// Sets the maximum lifetime of the keys.
+    pub fn set_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Sets the time old keys are kept to decrypt packets in flight. It
+    /// should exceed the maximum packet lifetime.
+    pub fn set_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type Finish = Box<dyn FnOnce(&[u8]) -> Result<StatelessTransportState, ReadError> + Send>;
+
+/// Rekey initiated by us.
+struct Pending {
+    request: Vec<u8>,
+    sent: Instant,
+    finish: Finish,
+}
+
+/// The keys of a session.
+pub(crate) struct Keys {
+    policy: RekeyPolicy,
+    /// Number of completed rekeys.
+    epoch: u64,
+    current: StatelessTransportState,
+    /// Old keys and when they are discarded.
+    previous: Option<(StatelessTransportState, Instant)>,
+    /// New keys that the peer did not use yet.
+    next: Option<StatelessTransportState>,
+    pending: Option<Pending>,
+    /// Last answered request and the response.
+    answered: Option<(Vec<u8>, Vec<u8>)>,
+    bytes: u64,
+    messages: u64,
+    since: Instant,
+}
+
+impl Keys {
+    pub fn new(state: StatelessTransportState, policy: RekeyPolicy) -> Self {
+        Self {
+            policy,
+            epoch: 0,
+            current: state,
+            previous: None,
+            next: None,
+            pending: None,
+            answered: None,
+            bytes: 0,
+            messages: 0,
+            since: Instant::now(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the nonce bit of the current keys.
+    pub fn phase(&self) -> u64 {
+        if self.epoch % 2 == 1 {
+            PHASE
+        } else {
+            0
+        }
+    }
+
+    /// Encrypts a message with the current keys.
+    pub fn seal(&self, nonce: u64, payload: &mut [u8]) -> [u8; disco::TAG_LEN] {
+        self.current.write_message(nonce, payload)
+    }
+
+    /// Decrypts a message with the keys selected by the nonce. Returns
+    /// `false` if it fails to authenticate.
+    pub fn open(
+        &mut self,
+        nonce: u64,
+        payload: &mut [u8],
+        tag: [u8; disco::TAG_LEN],
+        now: Instant,
+    ) -> bool {
+        self.expire(now);
+        if nonce & PHASE == self.phase() {
+            return self.current.read_message(nonce, payload, tag).is_ok();
+        }
+        if let Some(next) = self.next.as_ref() {
+            if next.read_message(nonce, payload, tag).is_err() {
+                return false;
+            }
+            // The peer switched to the new keys.
+            let next = self.next.take().unwrap();
+            self.rotate(next, now);
+            return true;
+        }
+        match self.previous.as_ref() {
+            Some((previous, _)) => previous.read_message(nonce, payload, tag).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Accounts for a sent message. Returns a rekey request if one needs to
+    /// be sent.
+    pub fn sent(&mut self, len: usize, now: Instant) -> Option<Vec<u8>> {
+        self.bytes = self.bytes.saturating_add(len as u64);
+        self.messages = self.messages.saturating_add(1);
+        self.expire(now);
+        if let Some(pending) = self.pending.as_mut() {
+            if now - pending.sent < RETRY {
+                return None;
+            }
+            pending.sent = now;
+            return Some(pending.request.clone());
+        }
+        if self.previous.is_some() || self.next.is_some() {
+            return None;
+        }
+        if self.bytes < self.policy.max_bytes
+            && self.messages < self.policy.max_messages
+            && now - self.since < self.policy.max_age
+        {
+            return None;
+        }
+        let mut session = SessionBuilder::new("NN").build_initiator();
+        let request = session.write_message(&[]);
+        let finish: Finish = Box::new(move |response: &[u8]| {
+            session.read_message(response)?;
+            Ok(session.into_stateless_transport_mode())
+        });
+        self.pending = Some(Pending {
+            request: request.clone(),
+            sent: now,
+            finish,
+        });
+        Some(request)
+    }
+
+    /// Handles a rekey request of the peer. Returns the response.
+    pub fn on_request(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        if let Some((answered, response)) = self.answered.as_ref() {
+            if answered.as_slice() == request {
+                return Some(response.clone());
+            }
+        }
+        if let Some(pending) = self.pending.as_ref() {
+            // Both sides started a rekey, the larger request wins.
+            if pending.request.as_slice() > request {
+                return None;
+            }
+            self.pending = None;
+        }
+        let mut session = SessionBuilder::new("NN").build_responder();
+        session.read_message(request).ok()?;
+        let response = session.write_message(&[]);
+        self.next = Some(session.into_stateless_transport_mode());
+        // There is a single nonce bit to select keys.
+        self.previous = None;
+        self.answered = Some((request.to_vec(), response.clone()));
+        Some(response)
+    }
+
+    /// Handles the response to our rekey request.
+    pub fn on_response(&mut self, response: &[u8], now: Instant) {
+        if let Some(pending) = self.pending.take() {
+            // A failed rekey is retried with a new request.
+            if let Ok(state) = (pending.finish)(response) {
+                self.rotate(state, now);
+            }
+        }
+    }
+
+    fn rotate(&mut self, state: StatelessTransportState, now: Instant) {
+        let previous = std::mem::replace(&mut self.current, state);
+        self.previous = Some((previous, now + self.policy.grace));
+        self.epoch += 1;
+        self.bytes = 0;
+        self.messages = 0;
+        self.since = now;
+    }
+
+    /// Discards the old keys after the grace period.
+    fn expire(&mut self, now: Instant) {
+        if let Some((_, deadline)) = self.previous.as_ref() {
+            if *deadline <= now {
+                self.previous = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Replay protection.
+
+/// Number of bits in a word of the bitmap.
+const WORD_BITS: u64 = 64;
+/// Number of words in the bitmap.
+const WORDS: usize = 32;
+/// Number of nonces behind the highest nonce that are tracked. One word is
+/// kept as slack, so advancing the window never clears a tracked nonce
+/// (RFC 6479).
+const WINDOW: u64 = (WORDS as u64 - 1) * WORD_BITS;
+
+/// Sliding window of received nonces.
+///
+/// Nonces are checked before decrypting a packet and marked after the
+/// packet was authenticated, otherwise a forged packet could advance the
+/// window.
+pub(crate) struct ReplayWindow {
+    /// One past the highest nonce received.
+    next: u64,
+    bitmap: [u64; WORDS],
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            next: 0,
+            bitmap: [0; WORDS],
+        }
+    }
+
+    fn position(nonce: u64) -> (usize, u64) {
+        let word = (nonce / WORD_BITS) as usize % WORDS;
+        let bit = 1 << (nonce % WORD_BITS);
+        (word, bit)
+    }
+
+    /// Returns `false` if the nonce was already received or is too old.
+    pub fn check(&self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            return true;
+        }
+        if self.next - nonce > WINDOW {
+            return false;
+        }
+        let (word, bit) = Self::position(nonce);
+        self.bitmap[word] & bit == 0
+    }
+
+    /// Marks the nonce as received. Returns `false` if it is a replay.
+    pub fn update(&mut self, nonce: u64) -> bool {
+        if !self.check(nonce) {
+            return false;
+        }
+        if nonce >= self.next {
+            let current = if self.next == 0 {
+                0
+            } else {
+                (self.next - 1) / WORD_BITS + 1
+            };
+            let target = nonce / WORD_BITS;
+            // Clear the words the window slides over.
+            let mut word = current;
+            while word <= target && word < current + WORDS as u64 {
+                self.bitmap[word as usize % WORDS] = 0;
+                word += 1;
+            }
+            self.next = nonce + 1;
+        }
+        let (word, bit) = Self::position(nonce);
+        self.bitmap[word] |= bit;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+        assert!(window.update(0));
+        assert!(!window.update(0));
+        assert!(window.update(2));
+        assert!(window.update(1));
+        assert!(!window.check(1));
+        assert!(!window.check(2));
+        assert!(window.check(3));
+
+        // Nonces within the window are accepted out of order.
+        assert!(window.update(5000));
+        assert!(window.update(4000));
+        assert!(!window.update(4000));
+        assert!(window.check(5000 - WINDOW + 1));
+        assert!(!window.check(5000 - WINDOW));
+
+        // Jumping ahead clears the bitmap.
+        assert!(window.update(1_000_000));
+        assert!(!window.check(5000));
+        assert!(window.check(1_000_000 - 1));
+        assert!(!window.update(1_000_000));
+    }
+
+    #[test]
+    fn test_replay_window_slide() {
+        let mut window = ReplayWindow::new();
+        for nonce in 0..10_000 {
+            assert!(window.update(nonce));
+            assert!(!window.check(nonce));
+            if nonce >= WINDOW {
+                assert!(!window.check(nonce - WINDOW));
+            }
+        }
+        // Every nonce within the window is tracked.
+        for nonce in (10_000 - WINDOW)..10_000 {
+            assert!(!window.check(nonce));
+        }
+    }
+}
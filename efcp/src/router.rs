@@ -0,0 +1,143 @@
+//! Routing of incoming channels to listeners.
+use crate::negotiation::Protocol;
+use std::collections::{HashMap, VecDeque};
+
+/// Maximum number of channels queued per protocol.
+const BACKLOG: usize = 64;
+
+/// Listeners of a protocol and the channels waiting to be accepted.
+struct Route<T> {
+    listeners: usize,
+    queue: VecDeque<T>,
+}
+
+/// Queues incoming channels by the negotiated protocol.
+pub(crate) struct Router<T> {
+    routes: HashMap<Protocol, Route<T>>,
+    /// Channels of protocols without a listener.
+    default: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+            default: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    /// Registers a listener of the protocol.
+    pub fn listen(&mut self, protocol: Protocol) {
+        let route = self.routes.entry(protocol).or_insert_with(|| Route {
+            listeners: 0,
+            queue: VecDeque::new(),
+        });
+        route.listeners += 1;
+    }
+
+    /// Unregisters a listener of the protocol. The queued channels are
+    /// dropped with the last listener.
+    pub fn unlisten(&mut self, protocol: &str) {
+        if let Some(route) = self.routes.get_mut(protocol) {
+            route.listeners -= 1;
+            if route.listeners == 0 {
+                self.routes.remove(protocol);
+            }
+        }
+    }
+
+    /// Returns the protocols that have a listener.
+    pub fn protocols(&self) -> impl Iterator<Item = &Protocol> {
+        self.routes.keys()
+    }
+
+    /// Queues a channel for the listeners of the protocol. Returns the
+    /// channel if the queue is full, which happens when no task accepts
+    /// the channels of the protocol.
+    pub fn push(&mut self, protocol: &str, channel: T) -> Result<(), T> {
+        let queue = match self.routes.get_mut(protocol) {
+            Some(route) => &mut route.queue,
+            None => &mut self.default,
+        };
+        if queue.len() >= BACKLOG {
+            return Err(channel);
+        }
+        queue.push_back(channel);
+        Ok(())
+    }
+
+    /// Queues an item for `EfcpSocket::incoming`, which listeners skip.
+    /// Returns the item if the queue is full.
+    pub fn push_default(&mut self, item: T) -> Result<(), T> {
+        if self.default.len() >= BACKLOG {
+            return Err(item);
+        }
+        self.default.push_back(item);
+        Ok(())
+    }
+
+    /// Returns the next channel of the protocol or of a protocol without
+    /// a listener if `protocol` is `None`.
+    pub fn pop(&mut self, protocol: Option<&str>) -> Option<T> {
+        match protocol {
+            Some(protocol) => self.routes.get_mut(protocol)?.queue.pop_front(),
+            None => self.default.pop_front(),
+        }
+    }
+
+    /// Marks the router as closed after the last incoming channel.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_router() {
+        let mut router = Router::new();
+        router.listen("/ping/1.0".into());
+        router.listen("/ping/1.0".into());
+        router.push("/ping/1.0", 1).unwrap();
+        router.push("/chat/1.0", 2).unwrap();
+        assert_eq!(router.protocols().count(), 1);
+        assert_eq!(router.pop(Some("/chat/1.0")), None);
+        assert_eq!(router.pop(None), Some(2));
+        assert_eq!(router.pop(Some("/ping/1.0")), Some(1));
+
+        // The route is removed with the last listener.
+        router.push("/ping/1.0", 3).unwrap();
+        router.unlisten("/ping/1.0");
+        assert_eq!(router.protocols().count(), 1);
+        router.unlisten("/ping/1.0");
+        assert_eq!(router.protocols().count(), 0);
+        assert_eq!(router.pop(Some("/ping/1.0")), None);
+        router.push("/ping/1.0", 4).unwrap();
+        assert_eq!(router.pop(None), Some(4));
+
+        // Items of the default queue are not yielded to listeners.
+        router.listen("/ping/1.0".into());
+        router.push_default(5).unwrap();
+        assert_eq!(router.pop(Some("/ping/1.0")), None);
+        assert_eq!(router.pop(None), Some(5));
+    }
+
+    #[test]
+    fn test_router_backlog() {
+        let mut router = Router::new();
+        for i in 0..BACKLOG {
+            router.push("/ping/1.0", i).unwrap();
+        }
+        assert_eq!(router.push("/ping/1.0", BACKLOG), Err(BACKLOG));
+        assert_eq!(router.pop(None), Some(0));
+        router.push("/ping/1.0", BACKLOG).unwrap();
+    }
+}
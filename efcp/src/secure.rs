@@ -1,24 +1,112 @@
+use crate::handshake::send_bytes;
+use crate::rekey::{Keys, RekeyPolicy, PHASE};
+use crate::replay::ReplayWindow;
 use async_trait::async_trait;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::BufMut;
 use channel::{derive_packet, BasePacket, Channel, Packet};
 use disco::{StatelessTransportState, TAG_LEN};
 use std::io::{Error, ErrorKind, Result};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
 
+/// Length of the trailer containing the padding length and frame type.
+const TRAILER_LEN: usize = 3;
+
+/// Type of a disco frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Frame {
+    /// Payload of the upper layer.
+    Data,
+    /// Rekey handshake request.
+    RekeyRequest,
+    /// Rekey handshake response.
+    RekeyResponse,
+    /// The channel was refused by the access control of the peer.
+    Refused,
+}
+
+impl Frame {
+    fn from_u8(frame: u8) -> Result<Self> {
+        match frame {
+            0 => Ok(Frame::Data),
+            1 => Ok(Frame::RekeyRequest),
+            2 => Ok(Frame::RekeyResponse),
+            3 => Ok(Frame::Refused),
+            _ => Err(Error::new(ErrorKind::Other, "invalid disco frame")),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Frame::Data => 0,
+            Frame::RekeyRequest => 1,
+            Frame::RekeyResponse => 2,
+            Frame::Refused => 3,
+        }
+    }
+}
+
+/// Padding policy of encrypted packets.
+///
+/// Packets are padded to hide the length of the payload. The length of the
+/// padding is encrypted with the payload and stripped by the receiver. The
+/// sizes refer to the length of the encrypted packet including the nonce
+/// and tag. Packets exceeding the size are not padded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Padding {
+    /// Packets are sent at their natural length.
+    Off,
+    /// Packets are padded to the next multiple of the bucket size.
+    Bucket(u16),
+    /// Packets are padded to the maximum transmission unit.
+    Mtu(u16),
+}
+
+impl Padding {
+    /// Returns the padded length of a packet.
+    fn padded_len(self, len: usize) -> usize {
+        match self {
+            Padding::Off | Padding::Bucket(0) => len,
+            Padding::Bucket(bucket) => {
+                let bucket = bucket as usize;
+                (len + bucket - 1) / bucket * bucket
+            }
+            Padding::Mtu(mtu) => len.max(mtu as usize),
+        }
+    }
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Padding::Off
+    }
+}
+
+/// Disco packet:
+///   nonce: u64
+///   tag: [u8; TAG_LEN]
+///   ciphertext:
+///     payload: [u8]
+///     padding: [u8; padding_len]
+///     padding_len: u16
+///     frame: u8
+///
+/// The most significant bit of the nonce selects the keys during a rekey.
 #[derive(Clone)]
 pub struct DiscoPacket<P: BasePacket>(P);
 
 impl<P: BasePacket> BasePacket for DiscoPacket<P> {
     fn new(payload_len: usize) -> Self {
-        let mut packet = P::new(payload_len + 8 + TAG_LEN);
+        let mut packet = P::new(payload_len + 8 + TAG_LEN + TRAILER_LEN);
         packet.put_u64_be(0);
         packet.put_slice(&[0u8; TAG_LEN][..]);
         Self(packet)
     }
 
     fn check(&self) -> Result<()> {
-        if self.0.payload().len() < 8 {
+        if self.0.payload().len() < 8 + TAG_LEN {
             return Err(Error::new(ErrorKind::Other, "invalid disco packet"));
         }
         Ok(())
@@ -32,10 +120,22 @@ impl<P: BasePacket> BasePacket for DiscoPacket<P> {
         &mut self.0.payload_mut()[(8 + TAG_LEN)..]
     }
 
+    fn truncate(&mut self, payload_len: usize) {
+        self.0.truncate(payload_len + 8 + TAG_LEN)
+    }
+
     fn debug(&self, ds: &mut std::fmt::DebugStruct) {
         self.0.debug(ds);
         ds.field("nonce", &self.nonce());
     }
+
+    fn ecn(&self) -> bool {
+        self.0.ecn()
+    }
+
+    fn set_ecn(&mut self, ecn: bool) {
+        self.0.set_ecn(ecn)
+    }
 }
 
 derive_packet!(DiscoPacket);
@@ -60,19 +160,116 @@ impl<P: BasePacket> DiscoPacket<P> {
     }
 }
 
+impl<P: BasePacket> DiscoPacket<P> {
+    /// Appends the padding and the trailer.
+    ///
+    /// Cloned packets, like retransmissions, have no spare capacity and
+    /// are copied.
+    fn pad(self, padding: Padding, frame: Frame) -> Self {
+        let len = self.0.payload().len() + TRAILER_LEN;
+        let padding_len = padding.padded_len(len) - len;
+        let mut packet = if padding_len > 0 || self.remaining_mut() < TRAILER_LEN {
+            let mut packet = P::new(len + padding_len);
+            packet.set_ecn(self.0.ecn());
+            packet.put_slice(self.0.payload());
+            packet.put_slice(&vec![0; padding_len]);
+            Self(packet)
+        } else {
+            self
+        };
+        packet.put_u16_be(padding_len as u16);
+        packet.put_u8(frame.to_u8());
+        packet
+    }
+
+    /// Strips the padding and the trailer of a decrypted packet.
+    fn unpad(&mut self) -> Result<Frame> {
+        let len = self.payload().len();
+        if len < TRAILER_LEN {
+            return Err(Error::new(ErrorKind::Other, "invalid disco padding"));
+        }
+        let trailer = &self.payload()[(len - TRAILER_LEN)..];
+        let padding_len = BigEndian::read_u16(&trailer[..2]) as usize;
+        let frame = Frame::from_u8(trailer[2])?;
+        if len < padding_len + TRAILER_LEN {
+            return Err(Error::new(ErrorKind::Other, "invalid disco padding"));
+        }
+        self.truncate(len - padding_len - TRAILER_LEN);
+        Ok(frame)
+    }
+}
+
 pub struct DiscoChannel<C> {
-    state: StatelessTransportState,
+    keys: Mutex<Keys>,
     channel: C,
     nonce: AtomicU64,
+    padding: Padding,
+    replay: Mutex<ReplayWindow>,
+    replays: AtomicU64,
+    /// Last handshake message received and sent.
+    handshake: Mutex<Option<(Vec<u8>, Vec<u8>)>>,
+    /// The peer refused the channel.
+    refused: AtomicBool,
 }
 
 impl<C: Channel> DiscoChannel<C> {
-    pub fn new(channel: C, state: StatelessTransportState) -> Self {
+    pub fn new(
+        channel: C,
+        state: StatelessTransportState,
+        padding: Padding,
+        rekey: RekeyPolicy,
+    ) -> Self {
         Self {
-            state,
+            keys: Mutex::new(Keys::new(state, rekey)),
             channel,
             nonce: AtomicU64::new(0),
+            padding,
+            replay: Mutex::new(ReplayWindow::new()),
+            replays: AtomicU64::new(0),
+            handshake: Mutex::new(None),
+            refused: AtomicBool::new(false),
+        }
+    }
+
+    /// Sets the last handshake message received and sent, if the handshake
+    /// finished with our message. Until the first packet of the peer is
+    /// authenticated, a retransmission of the received message means that
+    /// our message was lost and it is sent again.
+    pub fn set_handshake(&self, received: Vec<u8>, sent: Vec<u8>) {
+        *self.handshake.lock().unwrap() = Some((received, sent));
+    }
+
+    /// Encrypts and sends a frame.
+    async fn send_frame(&self, packet: DiscoPacket<C::Packet>, frame: Frame) -> Result<()> {
+        let mut packet = packet.pad(self.padding, frame);
+        let nonce = self.nonce.fetch_add(1, Ordering::SeqCst);
+        if nonce & PHASE > 0 {
+            return Err(Error::new(ErrorKind::Other, "disco nonces exhausted"));
+        }
+        {
+            let keys = self.keys.lock().unwrap();
+            let nonce = nonce | keys.phase();
+            packet.set_nonce(nonce);
+            let tag = keys.seal(nonce, packet.payload_mut());
+            packet.set_tag(tag);
         }
+        self.channel.send(packet.into_packet()).await
+    }
+
+    /// Notifies the peer that the channel was refused without waiting for
+    /// an acknowledgement. If the notification is lost the peer's channel
+    /// times out.
+    pub async fn refuse(&self) -> Result<()> {
+        self.send_frame(DiscoPacket::new(0), Frame::Refused).await
+    }
+
+    /// Returns the number of replayed packets that were rejected.
+    pub fn rejected_replays(&self) -> u64 {
+        self.replays.load(Ordering::SeqCst)
+    }
+
+    fn rejected_replay(&self) {
+        self.replays.fetch_add(1, Ordering::SeqCst);
     }
 }
 
@@ -80,25 +277,87 @@ impl<C: Channel> DiscoChannel<C> {
 impl<C: Channel> Channel for DiscoChannel<C> {
     type Packet = DiscoPacket<C::Packet>;
 
-    async fn send(&self, mut packet: Self::Packet) -> Result<()> {
-        let nonce = self.nonce.fetch_add(1, Ordering::SeqCst);
-        packet.set_nonce(nonce);
-        let tag = self.state.write_message(nonce, packet.payload_mut());
-        packet.set_tag(tag);
-        self.channel.send(packet.into_packet()).await
+    async fn send(&self, packet: Self::Packet) -> Result<()> {
+        let len = packet.payload().len();
+        let request = self.keys.lock().unwrap().sent(len, Instant::now());
+        if let Some(request) = request {
+            let request = DiscoPacket::from(&request[..]);
+            self.send_frame(request, Frame::RekeyRequest).await?;
+        }
+        self.send_frame(packet, Frame::Data).await
     }
 
+    /// Returns an error of kind `ConnectionRefused` once the peer refused
+    /// the channel.
     async fn recv(&self) -> Result<Self::Packet> {
-        let mut packet = DiscoPacket::parse(self.channel.recv().await?)?;
-        let nonce = packet.nonce();
-        let tag = packet.tag();
-        self.state
-            .read_message(nonce, packet.payload_mut(), tag)
-            .map_err(|err| Error::new(ErrorKind::Other, format!("{:?}", err)))?;
-        Ok(packet)
+        loop {
+            if self.refused.load(Ordering::SeqCst) {
+                return Err(refused());
+            }
+            // Packets that fail to authenticate are dropped, otherwise a
+            // single spoofed or stale packet would tear down the channel.
+            let packet = self.channel.recv().await?;
+            let retransmit = match self.handshake.lock().unwrap().as_ref() {
+                Some((received, sent)) if &received[..] == packet.payload() => Some(sent.clone()),
+                _ => None,
+            };
+            if let Some(sent) = retransmit {
+                send_bytes(&self.channel, &sent).await?;
+                continue;
+            }
+            let mut packet = match DiscoPacket::parse(packet) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+            let nonce = packet.nonce();
+            if !self.replay.lock().unwrap().check(nonce & !PHASE) {
+                self.rejected_replay();
+                continue;
+            }
+            let tag = packet.tag();
+            let now = Instant::now();
+            let authentic = {
+                let mut keys = self.keys.lock().unwrap();
+                keys.open(nonce, packet.payload_mut(), tag, now)
+            };
+            if !authentic {
+                continue;
+            }
+            let frame = match packet.unpad() {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            // Only authenticated nonces advance the replay window.
+            if !self.replay.lock().unwrap().update(nonce & !PHASE) {
+                self.rejected_replay();
+                continue;
+            }
+            // The peer finished the handshake.
+            self.handshake.lock().unwrap().take();
+            match frame {
+                Frame::Data => return Ok(packet),
+                Frame::RekeyRequest => {
+                    let response = self.keys.lock().unwrap().on_request(packet.payload());
+                    if let Some(response) = response {
+                        let response = DiscoPacket::from(&response[..]);
+                        self.send_frame(response, Frame::RekeyResponse).await?;
+                    }
+                }
+                Frame::RekeyResponse => {
+                    let mut keys = self.keys.lock().unwrap();
+                    keys.on_response(packet.payload(), now);
+                }
+                Frame::Refused => self.refused.store(true, Ordering::SeqCst),
+            }
+        }
     }
 }
 
+/// Returns the error of a channel refused by the peer.
+fn refused() -> Error {
+    Error::new(ErrorKind::ConnectionRefused, "channel refused by the peer")
+}
+
 impl<C> core::ops::Deref for DiscoChannel<C> {
     type Target = C;
 
@@ -111,24 +370,62 @@ impl<C> core::ops::Deref for DiscoChannel<C> {
 mod tests {
     use super::*;
     use async_std::task;
+    use bytes::BytesMut;
+    use channel::Loopback;
     use disco::SessionBuilder;
     use dtp::DtpSocket;
+    use std::time::Duration;
 
-    async fn disco_channel() {
+    fn sessions() -> (StatelessTransportState, StatelessTransportState) {
         let mut s1 = SessionBuilder::new("NN").build_initiator();
         let mut s2 = SessionBuilder::new("NN").build_responder();
         let m1 = s1.write_message(&[]);
         s2.read_message(&m1).unwrap();
         let m2 = s2.write_message(&[]);
         s1.read_message(&m2).unwrap();
-        let t1 = s1.into_stateless_transport_mode();
-        let t2 = s2.into_stateless_transport_mode();
+        (
+            s1.into_stateless_transport_mode(),
+            s2.into_stateless_transport_mode(),
+        )
+    }
+
+    struct Pipe {
+        tx: Loopback,
+        rx: Loopback,
+    }
+
+    #[async_trait]
+    impl Channel for Pipe {
+        type Packet = BytesMut;
+
+        async fn send(&self, packet: Self::Packet) -> Result<()> {
+            self.tx.send(packet).await
+        }
+
+        async fn recv(&self) -> Result<Self::Packet> {
+            self.rx.recv().await
+        }
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let a = Loopback::default();
+        let b = Loopback::default();
+        let p1 = Pipe {
+            tx: a.clone(),
+            rx: b.clone(),
+        };
+        let p2 = Pipe { tx: b, rx: a };
+        (p1, p2)
+    }
+
+    async fn disco_channel() {
+        let (t1, t2) = sessions();
         let d1 = DtpSocket::bind("/ip4/127.0.0.1").await.unwrap();
         let d2 = DtpSocket::bind("/ip4/127.0.0.1").await.unwrap();
         let c1 = d1.outgoing(d2.local_addr().unwrap(), 0).unwrap();
         let c2 = d2.outgoing(d1.local_addr().unwrap(), 0).unwrap();
-        let c1 = DiscoChannel::new(c1, t1);
-        let c2 = DiscoChannel::new(c2, t2);
+        let c1 = DiscoChannel::new(c1, t1, Padding::Bucket(64), RekeyPolicy::new());
+        let c2 = DiscoChannel::new(c2, t2, Padding::Mtu(1200), RekeyPolicy::new());
         println!("setup finished");
         c1.send("ping".into()).await.unwrap();
         let m1 = c2.recv().await.unwrap();
@@ -142,4 +439,97 @@ mod tests {
     fn test_disco_channel() {
         task::block_on(disco_channel());
     }
+
+    #[test]
+    fn test_replay() {
+        let (t1, t2) = sessions();
+        let (p1, p2) = pipe();
+        let link = p2.rx.clone();
+        let c1 = DiscoChannel::new(p1, t1, Padding::Off, RekeyPolicy::new());
+        let c2 = DiscoChannel::new(p2, t2, Padding::Off, RekeyPolicy::new());
+        task::block_on(async {
+            c1.send("ping".into()).await.unwrap();
+            let ct = link.recv().await.unwrap();
+            link.send(ct.clone()).await.unwrap();
+            link.send(ct).await.unwrap();
+            c1.send("pong".into()).await.unwrap();
+            assert_eq!(c2.recv().await.unwrap().payload(), b"ping");
+            assert_eq!(c2.recv().await.unwrap().payload(), b"pong");
+            assert_eq!(c2.rejected_replays(), 1);
+        });
+    }
+
+    #[test]
+    fn test_rekey() {
+        let (t1, t2) = sessions();
+        let (p1, p2) = pipe();
+        let link = p1.rx.clone();
+        let policy = RekeyPolicy::new()
+            .set_max_messages(4)
+            .set_grace(Duration::from_millis(50));
+        let c1 = DiscoChannel::new(p1, t1, Padding::Off, policy);
+        let c2 = DiscoChannel::new(p2, t2, Padding::Off, RekeyPolicy::new());
+        task::block_on(async {
+            // The rekey request is sent once the limit is reached.
+            for i in 0..5u8 {
+                c1.send((&[i][..]).into()).await.unwrap();
+            }
+            for i in 0..5u8 {
+                assert_eq!(c2.recv().await.unwrap().payload(), &[i]);
+            }
+            // The initiator switches keys when it receives the response.
+            // Packets encrypted with the old keys are accepted during the
+            // grace period.
+            c2.send("old".into()).await.unwrap();
+            assert_eq!(c1.recv().await.unwrap().payload(), b"old");
+            assert_eq!(c1.keys.lock().unwrap().epoch(), 1);
+            c2.send("late".into()).await.unwrap();
+            let late = link.recv().await.unwrap();
+            // The responder switches keys when it receives the first packet
+            // encrypted with the new keys.
+            c1.send("new".into()).await.unwrap();
+            assert_eq!(c2.recv().await.unwrap().payload(), b"new");
+            assert_eq!(c2.keys.lock().unwrap().epoch(), 1);
+            // The old keys are discarded after the grace period.
+            task::sleep(Duration::from_millis(60)).await;
+            link.send(late).await.unwrap();
+            c2.send("pong".into()).await.unwrap();
+            assert_eq!(c1.recv().await.unwrap().payload(), b"pong");
+        });
+    }
+
+    #[test]
+    fn test_refuse() {
+        let (t1, t2) = sessions();
+        let (p1, p2) = pipe();
+        let c1 = DiscoChannel::new(p1, t1, Padding::Off, RekeyPolicy::new());
+        let c2 = DiscoChannel::new(p2, t2, Padding::Off, RekeyPolicy::new());
+        task::block_on(async {
+            c1.refuse().await.unwrap();
+            let err = c2.recv().await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+            let err = c2.recv().await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        });
+    }
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(Padding::Off.padded_len(30), 30);
+        assert_eq!(Padding::Bucket(64).padded_len(30), 64);
+        assert_eq!(Padding::Bucket(64).padded_len(64), 64);
+        assert_eq!(Padding::Bucket(64).padded_len(65), 128);
+        assert_eq!(Padding::Mtu(1200).padded_len(30), 1200);
+        assert_eq!(Padding::Mtu(1200).padded_len(1300), 1300);
+        let len = 8 + TAG_LEN + 4 + TRAILER_LEN;
+        for &padding in &[Padding::Off, Padding::Bucket(64), Padding::Mtu(1200)] {
+            let packet = DiscoPacket::<BytesMut>::from("ping");
+            let mut packet = packet.clone().pad(padding, Frame::Data);
+            assert_eq!(packet.0.len(), padding.padded_len(len));
+            assert_eq!(packet.unpad().unwrap(), Frame::Data);
+            assert_eq!(packet.payload(), b"ping");
+        }
+        let mut packet = DiscoPacket::<BytesMut>::from(&[0xff, 0xff, 0][..]);
+        assert!(packet.unpad().is_err());
+    }
 }